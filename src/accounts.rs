@@ -0,0 +1,376 @@
+use std::{
+    future::Future,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use reqwest::Client;
+use serde::Serialize;
+use tokio::sync::{watch, Notify};
+
+use crate::{
+    auth::{AccessToken, AuthError, PixivAuth},
+    helper::find_cause,
+    pixiv::PixivError,
+    token_store::TokenStore,
+};
+
+/// First delay before retrying a failed token refresh, doubling on each failure
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// How long a request whose token pixiv rejected waits for a replacement before giving up
+const RENEW_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How the pool picks an account for each upstream request
+#[derive(Clone, Copy, Debug)]
+pub enum Selection {
+    RoundRobin,
+    LeastRecentlyUsed,
+}
+
+impl FromStr for Selection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "lru" => Ok(Self::LeastRecentlyUsed),
+            selection => anyhow::bail!("unknown account selection: {selection}"),
+        }
+    }
+}
+
+/// Several pixiv accounts sharing the upstream load, so one rate limited or banned account
+/// does not take phixiv down with it.
+///
+/// Each account's tokens are refreshed ahead of expiry by a background task, which publishes
+/// them through a watch channel so requests never wait on a refresh.
+pub struct AccountPool {
+    accounts: Vec<Account>,
+    selection: Selection,
+    next: AtomicUsize,
+    started: Instant,
+}
+
+struct Account {
+    token: watch::Receiver<Option<AccessToken>>,
+    health: Arc<Health>,
+}
+
+/// Benching state of an account, shared with the leases handed out for it and its token task.
+struct Health {
+    index: usize,
+    bench_duration: Duration,
+    benched_until: Mutex<Option<Instant>>,
+    /// Milliseconds since the pool started, for least recently used selection
+    last_used: AtomicU64,
+    /// Wakes the token task to refresh early, after pixiv rejected the current token
+    renew: Notify,
+}
+
+/// Sign-in state of an account, as reported by the readiness check
+#[derive(Serialize)]
+pub struct AccountStatus {
+    pub index: usize,
+    /// Whether the account holds an unexpired access token
+    pub valid: bool,
+    /// Seconds until the access token expires
+    pub expires_in: Option<u64>,
+    pub benched: bool,
+}
+
+/// An account picked for one request, see [`AccountPool::lease`].
+pub struct Lease {
+    pub access_token: String,
+    token: watch::Receiver<Option<AccessToken>>,
+    health: Arc<Health>,
+}
+
+impl Health {
+    fn benched(&self) -> bool {
+        self.benched_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    fn bench(&self, reason: &str) {
+        tracing::warn!(
+            account = self.index,
+            reason,
+            seconds = self.bench_duration.as_secs(),
+            "benching pixiv account"
+        );
+
+        *self.benched_until.lock().unwrap() = Some(Instant::now() + self.bench_duration);
+    }
+}
+
+impl Account {
+    fn available(&self) -> bool {
+        self.token
+            .borrow()
+            .as_ref()
+            .is_some_and(|token| !token.expired())
+            && !self.health.benched()
+    }
+}
+
+impl AccountPool {
+    /// Signs every account in and starts refreshing their tokens in the background, failing
+    /// only if no account could sign in.
+    pub async fn login(
+        client: &Client,
+        refresh_tokens: Vec<String>,
+        store: Option<Arc<TokenStore>>,
+        selection: Selection,
+        bench_duration: Duration,
+    ) -> anyhow::Result<Self> {
+        let mut accounts = Vec::with_capacity(refresh_tokens.len());
+
+        for (index, refresh_token) in refresh_tokens.into_iter().enumerate() {
+            let mut auth = PixivAuth::load(refresh_token, store.clone()).await;
+
+            let token = match auth.refresh(client).await {
+                Ok(token) => Some(token),
+                Err(error) => {
+                    tracing::warn!(account = index, %error, "failed to sign in to pixiv");
+                    None
+                }
+            };
+
+            let (sender, receiver) = watch::channel(token);
+
+            let health = Arc::new(Health {
+                index,
+                bench_duration,
+                benched_until: Mutex::new(None),
+                last_used: AtomicU64::new(0),
+                renew: Notify::new(),
+            });
+
+            tokio::spawn(manage_tokens(auth, sender, health.clone(), client.clone()));
+
+            accounts.push(Account {
+                token: receiver,
+                health,
+            });
+        }
+
+        if !accounts.iter().any(Account::available) {
+            return Err(AuthError::Exhausted.into());
+        }
+
+        tracing::info!(accounts = accounts.len(), "signed in to pixiv");
+
+        Ok(Self {
+            accounts,
+            selection,
+            next: AtomicUsize::new(0),
+            started: Instant::now(),
+        })
+    }
+
+    pub fn status(&self) -> Vec<AccountStatus> {
+        self.accounts
+            .iter()
+            .map(|account| {
+                let token = account.token.borrow();
+
+                AccountStatus {
+                    index: account.health.index,
+                    valid: token.as_ref().is_some_and(|token| !token.expired()),
+                    expires_in: token.as_ref().map(|token| {
+                        token
+                            .expires_at
+                            .saturating_duration_since(Instant::now())
+                            .as_secs()
+                    }),
+                    benched: account.health.benched(),
+                }
+            })
+            .collect()
+    }
+
+    /// Whether any account can currently be leased.
+    pub fn available(&self) -> bool {
+        self.accounts.iter().any(Account::available)
+    }
+
+    /// Picks an account with a valid access token that is not benched.
+    pub fn lease(&self) -> Result<Lease, AuthError> {
+        let account = match self.selection {
+            Selection::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+
+                (0..self.accounts.len())
+                    .map(|offset| &self.accounts[(start + offset) % self.accounts.len()])
+                    .find(|account| account.available())
+            }
+            Selection::LeastRecentlyUsed => self
+                .accounts
+                .iter()
+                .filter(|account| account.available())
+                .min_by_key(|account| account.health.last_used.load(Ordering::Relaxed)),
+        };
+
+        let Some(account) = account else {
+            return Err(AuthError::Exhausted);
+        };
+
+        let Some(token) = account.token.borrow().clone() else {
+            return Err(AuthError::Exhausted);
+        };
+
+        account
+            .health
+            .last_used
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+
+        Ok(Lease {
+            access_token: token.token,
+            token: account.token.clone(),
+            health: account.health.clone(),
+        })
+    }
+}
+
+/// Keeps an account's access token fresh, refreshing shortly before it expires, or early when
+/// asked to through [`Health::renew`], and retrying failures with exponential backoff. Runs
+/// until the pool is dropped.
+async fn manage_tokens(
+    mut auth: PixivAuth,
+    sender: watch::Sender<Option<AccessToken>>,
+    health: Arc<Health>,
+    client: Client,
+) {
+    let index = health.index;
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        let refresh_at = sender.borrow().as_ref().map(|token| token.refresh_at);
+
+        if let Some(refresh_at) = refresh_at {
+            tokio::select! {
+                _ = tokio::time::sleep_until(refresh_at.into()) => {}
+                _ = health.renew.notified() => {}
+                _ = sender.closed() => return,
+            }
+        }
+
+        match auth.refresh(&client).await {
+            Ok(token) => {
+                tracing::info!(account = index, "refreshed pixiv access token");
+
+                backoff = MIN_BACKOFF;
+                sender.send_replace(Some(token));
+            }
+            Err(error) => {
+                tracing::warn!(
+                    account = index,
+                    %error,
+                    retry_seconds = backoff.as_secs(),
+                    "failed to refresh pixiv access token"
+                );
+
+                if let AuthError::Rejected(_) = error {
+                    // The access token is unlikely to outlive the refresh token it came from
+                    sender.send_replace(None);
+                    health.bench("refresh token rejected");
+                }
+
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = sender.closed() => return,
+                }
+
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+impl Lease {
+    /// Runs `request` with this lease's access token, retrying once with a fresh token if pixiv
+    /// rejected it. The account is benched if pixiv rate limited it, if no fresh token arrived in
+    /// time, or if pixiv rejected the fresh token too.
+    pub async fn track<T, F>(&self, request: impl Fn(String) -> F) -> anyhow::Result<T>
+    where
+        F: Future<Output = anyhow::Result<T>>,
+    {
+        let mut result = request(self.access_token.clone()).await;
+
+        if invalid_token(&result) {
+            match self.renew().await {
+                Some(access_token) => {
+                    result = request(access_token).await;
+
+                    if invalid_token(&result) {
+                        self.health.bench("refreshed access token rejected");
+                    }
+                }
+                None => self.health.bench("access token not renewed in time"),
+            }
+        }
+
+        if let Err(error) = &result {
+            if matches!(
+                find_cause::<PixivError>(error),
+                Some(PixivError::RateLimited)
+            ) {
+                self.health.bench("rate limited");
+            }
+        }
+
+        result
+    }
+
+    /// Waits for a token to replace the one pixiv rejected, waking the token task to refresh.
+    async fn renew(&self) -> Option<String> {
+        let mut token = self.token.clone();
+
+        // Another request may have had the token replaced already
+        let current = token
+            .borrow_and_update()
+            .as_ref()
+            .map(|token| token.token.clone());
+
+        if current
+            .as_ref()
+            .is_some_and(|current| *current != self.access_token)
+        {
+            return current;
+        }
+
+        tracing::warn!(
+            account = self.health.index,
+            "pixiv rejected access token, refreshing early"
+        );
+
+        self.health.renew.notify_one();
+
+        tokio::time::timeout(RENEW_TIMEOUT, token.changed())
+            .await
+            .ok()?
+            .ok()?;
+
+        let renewed = token.borrow().as_ref().map(|token| token.token.clone());
+        renewed
+    }
+}
+
+/// Whether `result` failed because pixiv rejected the access token.
+fn invalid_token<T>(result: &anyhow::Result<T>) -> bool {
+    result.as_ref().is_err_and(|error| {
+        matches!(
+            find_cause::<PixivError>(error),
+            Some(PixivError::InvalidToken)
+        )
+    })
+}
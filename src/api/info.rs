@@ -5,9 +5,10 @@ use axum::{
     Json,
 };
 use serde::Deserialize;
-use tokio::sync::RwLock;
 
-use crate::{helper::PhixivError, pixiv::ArtworkListing, state::PhixivState};
+use crate::{pixiv::ArtworkListing, state::PhixivState};
+
+use super::ApiError;
 
 #[derive(Deserialize)]
 pub struct ArtworkInfoPath {
@@ -16,20 +17,29 @@ pub struct ArtworkInfoPath {
 }
 
 pub(super) async fn artwork_info_handler(
-    State(state): State<Arc<RwLock<PhixivState>>>,
+    State(state): State<Arc<PhixivState>>,
     Query(path): Query<ArtworkInfoPath>,
     Host(host): Host,
-) -> Result<Json<ArtworkListing>, PhixivError> {
-    let state = state.read().await;
-
-    Ok(Json(
-        ArtworkListing::get_listing(
-            path.language,
-            path.id,
-            &state.auth.access_token,
-            &host,
-            &state.client,
-        )
-        .await?,
-    ))
+) -> Result<Json<ArtworkListing>, ApiError> {
+    let account = state.accounts.lease()?;
+
+    let listing = account
+        .track(|access_token| {
+            ArtworkListing::get_listing(
+                path.language.clone(),
+                path.id.clone(),
+                access_token,
+                &host,
+                &state.upstream,
+                &state.listings,
+                &state.signer,
+            )
+        })
+        .await?;
+
+    let policy = state.config.sensitive_policy(&host);
+
+    policy.check(listing.restriction)?;
+
+    Ok(Json(listing.with_policy(policy)))
 }
@@ -1,19 +1,51 @@
 mod info;
+mod status;
+mod user;
 
 use std::sync::Arc;
 
-use axum::{middleware, routing::get, Router};
-use tokio::sync::RwLock;
+use axum::{
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde_json::json;
 
-use crate::state::{authorized_middleware, PhixivState};
+use crate::{helper::PhixivError, state::PhixivState};
 
-use self::info::artwork_info_handler;
+use self::{info::artwork_info_handler, status::status_handler, user::user_info_handler};
 
-pub fn api_router(state: Arc<RwLock<PhixivState>>) -> Router<Arc<RwLock<PhixivState>>> {
+pub use self::status::activity_handler;
+
+/// Errors from `/api`, rendered as JSON rather than plain text.
+pub struct ApiError(PhixivError);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let headers = self.0.headers();
+        let (status, message) = self.0.into_parts();
+
+        (
+            status,
+            headers,
+            Json(json!({ "error": { "status": status.as_u16(), "message": message } })),
+        )
+            .into_response()
+    }
+}
+
+impl<E> From<E> for ApiError
+where
+    E: Into<PhixivError>,
+{
+    fn from(value: E) -> Self {
+        Self(value.into())
+    }
+}
+
+pub fn api_router() -> Router<Arc<PhixivState>> {
     Router::new()
         .route("/info", get(artwork_info_handler))
-        .layer(middleware::from_fn_with_state(
-            state.clone(),
-            authorized_middleware,
-        ))
+        .route("/user", get(user_info_handler))
+        .route("/v1/statuses/:id", get(status_handler))
 }
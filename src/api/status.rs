@@ -0,0 +1,278 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Host, Path, State},
+    Json,
+};
+use serde::Serialize;
+use urlencoding::encode;
+
+use crate::{
+    config::Config,
+    pixiv::{ArtworkListing, ImageSize, Restriction},
+    sensitive::SensitivePolicy,
+    state::PhixivState,
+};
+
+use super::ApiError;
+
+/// An artwork as a Mastodon status, enough for clients unfurling statuses to show every page.
+#[derive(Serialize)]
+pub struct Status {
+    id: String,
+    uri: String,
+    url: String,
+    created_at: String,
+    edited_at: Option<String>,
+    account: Account,
+    content: String,
+    visibility: &'static str,
+    sensitive: bool,
+    spoiler_text: String,
+    media_attachments: Vec<MediaAttachment>,
+    mentions: Vec<()>,
+    tags: Vec<Tag>,
+    emojis: Vec<()>,
+    reblogs_count: u64,
+    favourites_count: u64,
+    replies_count: u64,
+    application: Application,
+}
+
+#[derive(Serialize)]
+struct Account {
+    id: String,
+    username: String,
+    acct: String,
+    display_name: String,
+    url: String,
+    avatar: String,
+    avatar_static: String,
+    note: String,
+    locked: bool,
+    bot: bool,
+    emojis: Vec<()>,
+    fields: Vec<()>,
+}
+
+#[derive(Serialize)]
+struct MediaAttachment {
+    id: String,
+    #[serde(rename = "type")]
+    media_type: &'static str,
+    url: String,
+    preview_url: String,
+    description: String,
+    meta: MediaMeta,
+}
+
+#[derive(Serialize)]
+struct MediaMeta {
+    original: MediaSize,
+}
+
+#[derive(Serialize)]
+struct MediaSize {
+    width: u32,
+    height: u32,
+    size: String,
+    aspect: f64,
+}
+
+impl From<ImageSize> for MediaSize {
+    fn from(size: ImageSize) -> Self {
+        Self {
+            width: size.width,
+            height: size.height,
+            size: format!("{}x{}", size.width, size.height),
+            aspect: size.width as f64 / size.height.max(1) as f64,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Tag {
+    name: String,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct Application {
+    name: String,
+    website: String,
+}
+
+/// Escapes text for inclusion in a status' HTML content.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl Status {
+    /// Builds the status, blurring or dropping the pages of restricted works according to
+    /// `policy`, as in the HTML embed.
+    fn new(listing: ArtworkListing, policy: SensitivePolicy, host: &str, config: &Config) -> Self {
+        let listing = listing.with_policy(policy);
+
+        let images = listing
+            .image_proxy_urls
+            .into_iter()
+            .zip(listing.image_sizes)
+            .collect::<Vec<_>>();
+
+        let page_count = images.len();
+
+        let media_attachments = images
+            .into_iter()
+            .enumerate()
+            .map(|(index, (url, size))| MediaAttachment {
+                id: format!("{}_p{index}", listing.illust_id),
+                media_type: "image",
+                preview_url: url.clone(),
+                url,
+                description: if page_count > 1 {
+                    format!("{} ({}/{page_count})", listing.title, index + 1)
+                } else {
+                    listing.title.clone()
+                },
+                meta: MediaMeta {
+                    original: size.into(),
+                },
+            })
+            .collect();
+
+        let tags = listing
+            .tags
+            .iter()
+            .map(|tag| {
+                let name = tag.trim_start_matches('#').to_string();
+
+                Tag {
+                    url: format!("https://www.pixiv.net/tags/{}", encode(&name)),
+                    name,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let hashtags = tags
+            .iter()
+            .map(|tag| {
+                format!(
+                    "<a href=\"{}\">#{}</a>",
+                    escape(&tag.url),
+                    escape(&tag.name)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let content = [
+            format!("<p><b>{}</b></p>", escape(&listing.title)),
+            String::from(if listing.ai_generated {
+                "<p>AI Generated</p>"
+            } else {
+                ""
+            }),
+            // pixiv already serves descriptions as HTML
+            if listing.description.is_empty() {
+                String::new()
+            } else {
+                format!("<p>{}</p>", listing.description)
+            },
+            if hashtags.is_empty() {
+                String::new()
+            } else {
+                format!("<p>{hashtags}</p>")
+            },
+        ]
+        .concat();
+
+        Self {
+            uri: format!(
+                "https://{host}/users/{}/statuses/{}",
+                listing.author_id, listing.illust_id
+            ),
+            id: listing.illust_id,
+            url: listing.url,
+            edited_at: (listing.uploaded_at != listing.created_at).then_some(listing.uploaded_at),
+            created_at: listing.created_at,
+            account: Account {
+                url: format!("https://www.pixiv.net/users/{}", listing.author_id),
+                id: listing.author_id,
+                username: listing.author_account.clone(),
+                acct: listing.author_account,
+                display_name: listing.author_name,
+                avatar: listing.author_avatar_proxy_url.clone(),
+                avatar_static: listing.author_avatar_proxy_url,
+                note: String::new(),
+                locked: false,
+                bot: false,
+                emojis: Vec::new(),
+                fields: Vec::new(),
+            },
+            content,
+            visibility: "public",
+            sensitive: listing.restriction != Restriction::Safe,
+            spoiler_text: listing.restriction.label().unwrap_or_default().to_string(),
+            media_attachments,
+            mentions: Vec::new(),
+            tags,
+            emojis: Vec::new(),
+            reblogs_count: 0,
+            favourites_count: listing.bookmarks,
+            replies_count: listing.comments,
+            application: Application {
+                name: config.provider_name.clone(),
+                website: config.provider_url.to_string(),
+            },
+        }
+    }
+}
+
+async fn status_response(
+    state: Arc<PhixivState>,
+    id: String,
+    host: String,
+) -> Result<Json<Status>, ApiError> {
+    let account = state.accounts.lease()?;
+
+    let listing = account
+        .track(|access_token| {
+            ArtworkListing::get_listing(
+                None,
+                id.clone(),
+                access_token,
+                &host,
+                &state.upstream,
+                &state.listings,
+                &state.signer,
+            )
+        })
+        .await?;
+
+    let policy = state.config.sensitive_policy(&host);
+
+    policy.check(listing.restriction)?;
+
+    Ok(Json(Status::new(listing, policy, &host, &state.config)))
+}
+
+/// Mastodon's `GET /api/v1/statuses/:id`, where `id` is an artwork id.
+pub(super) async fn status_handler(
+    State(state): State<Arc<PhixivState>>,
+    Path(id): Path<String>,
+    Host(host): Host,
+) -> Result<Json<Status>, ApiError> {
+    status_response(state, id, host).await
+}
+
+/// The status' `uri`, linked from artwork embeds as `application/activity+json`.
+pub async fn activity_handler(
+    State(state): State<Arc<PhixivState>>,
+    Path((_author_id, id)): Path<(String, String)>,
+    Host(host): Host,
+) -> Result<Json<Status>, ApiError> {
+    status_response(state, id, host).await
+}
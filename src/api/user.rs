@@ -0,0 +1,40 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Host, Query, State},
+    Json,
+};
+use serde::Deserialize;
+
+use crate::{pixiv::UserListing, state::PhixivState};
+
+use super::ApiError;
+
+#[derive(Deserialize)]
+pub struct UserInfoPath {
+    pub id: String,
+}
+
+pub(super) async fn user_info_handler(
+    State(state): State<Arc<PhixivState>>,
+    Query(path): Query<UserInfoPath>,
+    Host(host): Host,
+) -> Result<Json<UserListing>, ApiError> {
+    let account = state.accounts.lease()?;
+
+    let listing = account
+        .track(|access_token| {
+            UserListing::get_listing(
+                path.id.clone(),
+                access_token,
+                &host,
+                &state.upstream,
+                &state.signer,
+            )
+        })
+        .await?;
+
+    let policy = state.config.sensitive_policy(&host);
+
+    Ok(Json(listing.with_policy(policy, &host, &state.signer)))
+}
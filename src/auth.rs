@@ -1,5 +1,7 @@
 use std::{
     collections::HashMap,
+    fmt,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
@@ -7,14 +9,37 @@ use http::StatusCode;
 use reqwest::Client;
 use serde::Deserialize;
 
-use crate::helper;
+use crate::{helper, metrics, token_store::TokenStore};
 
-/// Token lifetime, actually 3600 seconds, but using 3500 to be safe
-const TOKEN_DURATION: u64 = 3500;
+/// How long before expiry access tokens are refreshed, at most
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
 
 const CLIENT_ID: &str = "MOBrBDS8blbauoSck0ZfDbtuzpyT";
 const CLIENT_SECRET: &str = "lsACyCD94FhDUtGTXi3QzcFE2uU1hqtDaKeqrdwj";
 
+/// Failure to obtain an access token, leaving phixiv unable to talk to pixiv.
+#[derive(Debug)]
+pub enum AuthError {
+    /// Pixiv rejected the refresh token
+    Rejected(StatusCode),
+    /// The token endpoint could not be reached or answered with garbage
+    Request(reqwest::Error),
+    /// Every account is benched or signed out
+    Exhausted,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rejected(status) => write!(f, "invalid credentials, status code {status}"),
+            Self::Request(error) => write!(f, "failed to reach pixiv's token endpoint: {error}"),
+            Self::Exhausted => write!(f, "no pixiv account is available"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
 #[derive(Debug, Deserialize)]
 struct AuthPayload {
     pub response: AuthResponse,
@@ -24,18 +49,35 @@ struct AuthPayload {
 struct AuthResponse {
     pub access_token: String,
     pub refresh_token: String,
+    /// Lifetime of the access token in seconds
+    pub expires_in: u64,
 }
 
-/// Pixiv authorization state manager, holds and manages refreshing access tokens for authorization.
-#[derive(Clone)]
+/// An access token and its lifetime.
+#[derive(Clone, Debug)]
+pub struct AccessToken {
+    pub token: String,
+    pub expires_at: Instant,
+    /// When to replace the token, a little before it expires
+    pub refresh_at: Instant,
+}
+
+impl AccessToken {
+    pub fn expired(&self) -> bool {
+        Instant::now() >= self.expires_at
+    }
+}
+
+/// Pixiv authorization for one account, exchanging its refresh token for access tokens.
 pub struct PixivAuth {
-    pub access_token: String,
     refresh_token: String,
-    expires_after: Instant,
+    /// Where rotated refresh tokens are kept across restarts, under `store_key`
+    store: Option<Arc<TokenStore>>,
+    store_key: String,
 }
 
 impl PixivAuth {
-    async fn authorize(client: &Client, refresh_token: &String) -> anyhow::Result<AuthResponse> {
+    async fn authorize(client: &Client, refresh_token: &str) -> Result<AuthResponse, AuthError> {
         let form_data = HashMap::from([
             ("client_id", CLIENT_ID),
             ("client_secret", CLIENT_SECRET),
@@ -44,44 +86,77 @@ impl PixivAuth {
             ("grant_type", "refresh_token"),
         ]);
 
-        let auth_response = client
-            .post("https://oauth.secure.pixiv.net/auth/token")
-            .headers(helper::headers())
-            .form(&form_data)
-            .send()
-            .await?;
+        let auth_response = metrics::upstream(
+            "oauth",
+            client
+                .post("https://oauth.secure.pixiv.net/auth/token")
+                .headers(helper::headers())
+                .form(&form_data),
+        )
+        .await
+        .map_err(AuthError::Request)?;
 
         match auth_response.status() {
             StatusCode::OK | StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND => {}
-            s => {
-                anyhow::bail!("invalid credentials, status code {s}")
-            }
+            s => return Err(AuthError::Rejected(s)),
         }
 
-        Ok(auth_response.json::<AuthPayload>().await?.response)
+        Ok(auth_response
+            .json::<AuthPayload>()
+            .await
+            .map_err(AuthError::Request)?
+            .response)
     }
 
-    pub fn expired(&self) -> bool {
-        Instant::now() > self.expires_after
-    }
+    /// Starts from the latest refresh token in `store`, or `configured_token` if it has none.
+    pub async fn load(configured_token: String, store: Option<Arc<TokenStore>>) -> Self {
+        let store_key = TokenStore::key(&configured_token);
 
-    pub async fn login(client: &Client, refresh_token: String) -> anyhow::Result<Self> {
-        let response = Self::authorize(client, &refresh_token).await?;
+        let refresh_token = match &store {
+            Some(store) => store.get(&store_key).await,
+            None => None,
+        };
 
-        Ok(Self {
-            access_token: response.access_token,
-            refresh_token: response.refresh_token,
-            expires_after: Instant::now() + Duration::from_secs(TOKEN_DURATION),
-        })
+        Self {
+            refresh_token: refresh_token.unwrap_or(configured_token),
+            store,
+            store_key,
+        }
     }
 
-    pub async fn refresh(&mut self, client: &Client) -> anyhow::Result<()> {
-        let response = Self::authorize(client, &self.refresh_token).await?;
+    /// Requests a new access token, keeping the refresh token pixiv rotates in with it.
+    pub async fn refresh(&mut self, client: &Client) -> Result<AccessToken, AuthError> {
+        let issued_at = Instant::now();
+
+        let response = Self::authorize(client, &self.refresh_token).await;
+
+        metrics::METRICS
+            .token_refreshes
+            .with_label_values(&[if response.is_ok() {
+                "success"
+            } else {
+                "failure"
+            }])
+            .inc();
 
-        self.access_token = response.access_token;
-        self.refresh_token = response.refresh_token;
-        self.expires_after = Instant::now() + Duration::from_secs(TOKEN_DURATION);
+        let response = response?;
 
-        Ok(())
+        if response.refresh_token != self.refresh_token {
+            self.refresh_token = response.refresh_token;
+
+            if let Some(store) = &self.store {
+                if let Err(error) = store.save(&self.store_key, &self.refresh_token).await {
+                    tracing::error!(%error, "failed to persist rotated refresh token");
+                }
+            }
+        }
+
+        let lifetime = Duration::from_secs(response.expires_in);
+
+        Ok(AccessToken {
+            token: response.access_token,
+            expires_at: issued_at + lifetime,
+            refresh_at: issued_at + lifetime - REFRESH_MARGIN.min(lifetime / 10),
+        })
     }
 }
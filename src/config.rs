@@ -0,0 +1,261 @@
+use std::{collections::HashMap, env, fs, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::Context;
+use url::Url;
+
+use crate::{
+    accounts::Selection,
+    format::TextFormat,
+    rate_limit::{Quota, TrustedProxies},
+    sensitive::SensitivePolicy,
+};
+
+/// Settings read once at startup, so misconfiguration is caught before serving traffic.
+///
+/// Every setting is named by its environment variable, e.g. `PORT`. Its value is taken from that
+/// variable, from the file named by `PORT_FILE` (for Docker secrets), or from the `port` key of
+/// the TOML file named by `CONFIG_FILE`, in that order. Empty values count as unset.
+pub struct Config {
+    pub port: u16,
+    pub refresh_tokens: Vec<String>,
+    pub account_selection: Selection,
+    pub account_bench_time: Duration,
+    pub token_store_path: Option<PathBuf>,
+    pub token_store_key: Option<String>,
+    pub bot_filtering: bool,
+    pub mosaic_default: bool,
+    pub mosaic_pages: usize,
+    /// Megabytes of memory for rendered images
+    pub render_cache_size: u64,
+    pub listing_cache_size: u64,
+    pub listing_cache_ttl: Duration,
+    pub listing_not_found_ttl: Duration,
+    pub image_cache_dir: Option<PathBuf>,
+    /// Megabytes of disk for proxied images
+    pub image_cache_size: u64,
+    pub proxy_secret: Option<String>,
+    pub proxy_url_ttl: Option<Duration>,
+    pub sensitive_policy: SensitivePolicy,
+    pub sensitive_policy_hosts: HashMap<String, SensitivePolicy>,
+    pub loki_url: Option<Url>,
+    pub environment: String,
+    pub provider_name: String,
+    pub provider_url: Url,
+    pub rate_limit_humans: Quota,
+    pub rate_limit_crawlers: Quota,
+    pub rate_limit_api: Quota,
+    pub trusted_proxies: TrustedProxies,
+    /// Requests in flight to pixiv at once, 0 for no limit
+    pub upstream_concurrency: usize,
+    pub upstream_queue_timeout: Duration,
+    pub embed_title_format: TextFormat,
+    pub embed_description_format: TextFormat,
+    /// Characters, 0 for no limit
+    pub embed_title_max_length: usize,
+    pub embed_description_max_length: usize,
+    /// Width the proxy scales embedded pages down to, 0 for pixiv's own size
+    pub embed_image_width: u32,
+}
+
+impl Config {
+    pub fn load() -> anyhow::Result<Self> {
+        let source = Source::open()?;
+
+        let refresh_tokens = match source.raw("PIXIV_REFRESH_TOKENS")? {
+            Some(tokens) => Some(tokens),
+            None => source.raw("PIXIV_REFRESH_TOKEN")?,
+        }
+        .unwrap_or_default()
+        .split(',')
+        .map(|token| token.trim().to_string())
+        .filter(|token| !token.is_empty())
+        .collect::<Vec<_>>();
+
+        if refresh_tokens.is_empty() {
+            anyhow::bail!("PIXIV_REFRESH_TOKEN or PIXIV_REFRESH_TOKENS must be set");
+        }
+
+        let mosaic_pages = source.get("MOSAIC_PAGES", 4)?;
+
+        if mosaic_pages == 0 {
+            anyhow::bail!("MOSAIC_PAGES must be at least 1");
+        }
+
+        let token_store_path = source.optional("TOKEN_STORE_PATH")?;
+        let token_store_key = source.optional("TOKEN_STORE_KEY")?;
+
+        if token_store_key.is_some() && token_store_path.is_none() {
+            anyhow::bail!("TOKEN_STORE_KEY is set without TOKEN_STORE_PATH");
+        }
+
+        let sensitive_policy_hosts = match source.raw("SENSITIVE_POLICY_HOSTS")? {
+            Some(hosts) => host_policies(&hosts).context("invalid SENSITIVE_POLICY_HOSTS")?,
+            None => HashMap::new(),
+        };
+
+        Ok(Self {
+            port: source.get("PORT", 3000)?,
+            refresh_tokens,
+            account_selection: source.get("ACCOUNT_SELECTION", Selection::RoundRobin)?,
+            account_bench_time: Duration::from_secs(source.get("ACCOUNT_BENCH_TIME", 300)?),
+            token_store_path,
+            token_store_key,
+            bot_filtering: source.get("BOT_FILTERING", false)?,
+            mosaic_default: source.get("MOSAIC_DEFAULT", false)?,
+            mosaic_pages,
+            render_cache_size: source.get("RENDER_CACHE_SIZE", 128)?,
+            listing_cache_size: source.get("LISTING_CACHE_SIZE", 1000)?,
+            listing_cache_ttl: Duration::from_secs(source.get("LISTING_CACHE_TTL", 300)?),
+            listing_not_found_ttl: Duration::from_secs(source.get("LISTING_NOT_FOUND_TTL", 60)?),
+            image_cache_dir: source.optional("IMAGE_CACHE_DIR")?,
+            image_cache_size: source.get("IMAGE_CACHE_SIZE", 1024)?,
+            proxy_secret: source.optional("PROXY_SECRET")?,
+            proxy_url_ttl: match source.get("PROXY_URL_TTL", 0)? {
+                0 => None,
+                seconds => Some(Duration::from_secs(seconds)),
+            },
+            sensitive_policy: source.get("SENSITIVE_POLICY", SensitivePolicy::Blur)?,
+            sensitive_policy_hosts,
+            loki_url: source.optional("LOKI_URL")?,
+            environment: source.get("ENVIRONMENT", String::from("development"))?,
+            provider_name: source.get("PROVIDER_NAME", String::from("phixiv"))?,
+            provider_url: source.get(
+                "PROVIDER_URL",
+                Url::parse("https://github.com/HazelTheWitch/phixiv")?,
+            )?,
+            rate_limit_humans: source.get("RATE_LIMIT_HUMANS", "120".parse()?)?,
+            rate_limit_crawlers: source.get("RATE_LIMIT_CRAWLERS", "0".parse()?)?,
+            rate_limit_api: source.get("RATE_LIMIT_API", "60".parse()?)?,
+            trusted_proxies: source.get("TRUSTED_PROXIES", TrustedProxies::default())?,
+            upstream_concurrency: source.get("UPSTREAM_CONCURRENCY", 64)?,
+            upstream_queue_timeout: Duration::from_secs(source.get("UPSTREAM_QUEUE_TIMEOUT", 5)?),
+            embed_title_format: source.get(
+                "EMBED_TITLE_FORMAT",
+                "{#restriction}[{restriction}] {/restriction}{title}".parse()?,
+            )?,
+            embed_description_format: source.get(
+                "EMBED_DESCRIPTION_FORMAT",
+                r"{#ai}{ai}\n\n{/ai}{#description}{description}\n{/description}{tags}".parse()?,
+            )?,
+            embed_title_max_length: source.get("EMBED_TITLE_MAX_LENGTH", 256)?,
+            embed_description_max_length: source.get("EMBED_DESCRIPTION_MAX_LENGTH", 4096)?,
+            embed_image_width: source.get("EMBED_IMAGE_WIDTH", 0)?,
+        })
+    }
+
+    /// Policy for embeds served on `host`, from `SENSITIVE_POLICY_HOSTS` falling back to
+    /// `SENSITIVE_POLICY`.
+    pub fn sensitive_policy(&self, host: &str) -> SensitivePolicy {
+        self.sensitive_policy_hosts
+            .get(host)
+            .copied()
+            .unwrap_or(self.sensitive_policy)
+    }
+}
+
+/// Parses `host=policy,...` pairs.
+fn host_policies(hosts: &str) -> anyhow::Result<HashMap<String, SensitivePolicy>> {
+    hosts
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (host, policy) = entry
+                .split_once('=')
+                .with_context(|| format!("expected host=policy, got {entry}"))?;
+
+            Ok((host.trim().to_string(), policy.parse()?))
+        })
+        .collect()
+}
+
+/// Where settings are looked up, see [`Config`].
+struct Source {
+    file: toml::Table,
+}
+
+impl Source {
+    fn open() -> anyhow::Result<Self> {
+        let file = match env::var("CONFIG_FILE") {
+            Ok(path) if !path.is_empty() => fs::read_to_string(&path)
+                .with_context(|| format!("failed to read CONFIG_FILE {path}"))?
+                .parse()
+                .with_context(|| format!("invalid CONFIG_FILE {path}"))?,
+            _ => toml::Table::new(),
+        };
+
+        Ok(Self { file })
+    }
+
+    /// Unparsed value of a setting. TOML arrays and tables are flattened into the same
+    /// `a,b` and `key=value,...` lists accepted from the environment.
+    fn raw(&self, name: &str) -> anyhow::Result<Option<String>> {
+        if let Some(value) = env::var(name).ok().filter(|value| !value.is_empty()) {
+            return Ok(Some(value));
+        }
+
+        let file_name = format!("{name}_FILE");
+
+        if let Some(path) = env::var(&file_name).ok().filter(|path| !path.is_empty()) {
+            let value = fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {file_name} {path}"))?;
+
+            // Secrets are usually written with a trailing newline
+            return Ok(Some(value.trim_end().to_string()).filter(|value| !value.is_empty()));
+        }
+
+        let Some(value) = self.file.get(&name.to_lowercase()) else {
+            return Ok(None);
+        };
+
+        let value = match value {
+            toml::Value::Array(items) => items.iter().map(scalar).collect::<Option<Vec<_>>>(),
+            toml::Value::Table(table) => table
+                .iter()
+                .map(|(key, value)| Some(format!("{key}={}", scalar(value)?)))
+                .collect::<Option<Vec<_>>>(),
+            value => scalar(value).map(|value| vec![value]),
+        }
+        .with_context(|| {
+            format!(
+                "unsupported value for {} in CONFIG_FILE",
+                name.to_lowercase()
+            )
+        })?
+        .join(",");
+
+        Ok(Some(value).filter(|value| !value.is_empty()))
+    }
+
+    fn optional<T>(&self, name: &str) -> anyhow::Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Into<anyhow::Error>,
+    {
+        self.raw(name)?
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(Into::into)
+                    .with_context(|| format!("invalid {name}"))
+            })
+            .transpose()
+    }
+
+    fn get<T>(&self, name: &str, default: T) -> anyhow::Result<T>
+    where
+        T: FromStr,
+        T::Err: Into<anyhow::Error>,
+    {
+        Ok(self.optional(name)?.unwrap_or(default))
+    }
+}
+
+fn scalar(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value.clone()),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        toml::Value::Boolean(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
@@ -0,0 +1,341 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Size capped, least recently used cache of files on disk.
+///
+/// Entries are written to a temporary file and renamed into place once complete,
+/// so readers never observe a partially written entry. Each file starts with a
+/// length-prefixed metadata block followed by the body.
+#[derive(Clone)]
+pub struct DiskCache {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    directory: PathBuf,
+    capacity: u64,
+    index: Mutex<Index>,
+    temp_counter: AtomicU64,
+}
+
+/// Tracks entry sizes and recency, `ticks` orders entries from least to most recently used.
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, IndexEntry>,
+    ticks: BTreeMap<u64, String>,
+    total_size: u64,
+    tick: u64,
+}
+
+/// A cached entry, `file` is positioned at the start of the body.
+pub struct CacheEntry {
+    pub metadata: Vec<u8>,
+    pub file: File,
+    pub body_offset: u64,
+    pub body_len: u64,
+}
+
+struct IndexEntry {
+    size: u64,
+    tick: u64,
+}
+
+impl Index {
+    fn touch(&mut self, name: &str) -> bool {
+        let Some(entry) = self.entries.get_mut(name) else {
+            return false;
+        };
+
+        self.ticks.remove(&entry.tick);
+        self.tick += 1;
+        entry.tick = self.tick;
+        self.ticks.insert(self.tick, name.to_string());
+
+        true
+    }
+
+    fn insert(&mut self, name: String, size: u64) {
+        self.remove(&name);
+
+        self.tick += 1;
+        self.ticks.insert(self.tick, name.clone());
+        self.entries.insert(
+            name,
+            IndexEntry {
+                size,
+                tick: self.tick,
+            },
+        );
+        self.total_size += size;
+    }
+
+    fn remove(&mut self, name: &str) {
+        if let Some(entry) = self.entries.remove(name) {
+            self.ticks.remove(&entry.tick);
+            self.total_size -= entry.size;
+        }
+    }
+
+    /// Drops least recently used entries until the total fits in `capacity`, returning their names.
+    fn evict(&mut self, capacity: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+
+        while self.total_size > capacity {
+            let Some((_, name)) = self.ticks.pop_first() else {
+                break;
+            };
+
+            if let Some(entry) = self.entries.remove(&name) {
+                self.total_size -= entry.size;
+            }
+
+            evicted.push(name);
+        }
+
+        evicted
+    }
+}
+
+impl DiskCache {
+    /// Opens the cache in `directory`, indexing any entries left by a previous run.
+    pub async fn open(directory: impl Into<PathBuf>, capacity: u64) -> io::Result<Self> {
+        let directory = directory.into();
+
+        tokio::fs::create_dir_all(&directory).await?;
+
+        let mut existing = Vec::new();
+        let mut entries = tokio::fs::read_dir(&directory).await?;
+
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+
+            if path
+                .extension()
+                .is_some_and(|extension| extension == TEMP_EXTENSION)
+            {
+                tokio::fs::remove_file(&path).await?;
+                continue;
+            }
+
+            let metadata = entry.metadata().await?;
+
+            if !metadata.is_file() {
+                continue;
+            }
+
+            existing.push((
+                metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                entry.file_name().to_string_lossy().into_owned(),
+                metadata.len(),
+            ));
+        }
+
+        existing.sort();
+
+        let mut index = Index::default();
+
+        for (_, name, size) in existing {
+            index.insert(name, size);
+        }
+
+        let cache = Self {
+            inner: Arc::new(Inner {
+                directory,
+                capacity,
+                index: Mutex::new(index),
+                temp_counter: AtomicU64::new(0),
+            }),
+        };
+
+        cache.evict().await;
+
+        Ok(cache)
+    }
+
+    /// Bytes stored, and the capacity they are kept under.
+    pub fn usage(&self) -> (u64, u64) {
+        (
+            self.inner.index.lock().unwrap().total_size,
+            self.inner.capacity,
+        )
+    }
+
+    /// Checks new entries can still be written, by writing and removing a temporary file.
+    pub async fn check_writable(&self) -> io::Result<()> {
+        let temp_path = self.inner.directory.join(format!(
+            "check.{}.{TEMP_EXTENSION}",
+            self.inner.temp_counter.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let mut file = File::create(&temp_path).await?;
+        file.write_all(b"ok").await?;
+        drop(file);
+
+        tokio::fs::remove_file(&temp_path).await
+    }
+
+    fn file_name(key: &str) -> String {
+        format!("{:x}", Sha256::digest(key.as_bytes()))
+    }
+
+    /// Opens the entry for `key`, marking it as recently used.
+    pub async fn get(&self, key: &str) -> Option<CacheEntry> {
+        let name = Self::file_name(key);
+
+        if !self.inner.index.lock().unwrap().touch(&name) {
+            return None;
+        }
+
+        match Self::open_entry(self.inner.directory.join(&name)).await {
+            Ok(entry) => Some(entry),
+            Err(error) => {
+                tracing::warn!(name, %error, "failed to read image cache entry");
+                self.inner.index.lock().unwrap().remove(&name);
+                None
+            }
+        }
+    }
+
+    async fn open_entry(path: PathBuf) -> io::Result<CacheEntry> {
+        let mut file = File::open(path).await?;
+        let len = file.metadata().await?.len();
+
+        let metadata_len = file.read_u32_le().await? as u64;
+        let body_offset = 4 + metadata_len;
+
+        if body_offset > len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated metadata",
+            ));
+        }
+
+        let mut metadata = vec![0; metadata_len as usize];
+        file.read_exact(&mut metadata).await?;
+
+        Ok(CacheEntry {
+            metadata,
+            file,
+            body_offset,
+            body_len: len - body_offset,
+        })
+    }
+
+    /// Starts writing a new entry for `key`, which becomes visible once committed.
+    pub async fn writer(&self, key: &str, metadata: &[u8]) -> io::Result<CacheWriter> {
+        let name = Self::file_name(key);
+
+        let temp_path = self.inner.directory.join(format!(
+            "{name}.{}.{TEMP_EXTENSION}",
+            self.inner.temp_counter.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let mut writer = CacheWriter {
+            cache: self.clone(),
+            name,
+            file: Some(File::create(&temp_path).await?),
+            temp_path,
+            size: 0,
+            body_offset: 0,
+        };
+
+        let metadata_len = u32::try_from(metadata.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "metadata too large"))?;
+
+        writer.write(&metadata_len.to_le_bytes()).await?;
+        writer.write(metadata).await?;
+        writer.body_offset = writer.size;
+
+        Ok(writer)
+    }
+
+    async fn evict(&self) {
+        let evicted = self.inner.index.lock().unwrap().evict(self.inner.capacity);
+
+        for name in evicted {
+            if let Err(error) = tokio::fs::remove_file(self.inner.directory.join(&name)).await {
+                tracing::warn!(name, %error, "failed to evict image cache entry");
+            }
+        }
+    }
+}
+
+/// An entry being written, discarded if dropped before [`CacheWriter::commit`].
+pub struct CacheWriter {
+    cache: DiskCache,
+    name: String,
+    temp_path: PathBuf,
+    file: Option<File>,
+    size: u64,
+    body_offset: u64,
+}
+
+impl CacheWriter {
+    /// Appends `chunk`, visible to other readers of [`CacheWriter::temp_path`] once this
+    /// returns.
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(chunk).await?;
+            file.flush().await?;
+            self.size += chunk.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    /// The partially written entry, removed or renamed once the writer is done with it.
+    pub fn temp_path(&self) -> &Path {
+        &self.temp_path
+    }
+
+    /// Where the body starts in the entry, after the metadata.
+    pub fn body_offset(&self) -> u64 {
+        self.body_offset
+    }
+
+    /// Atomically moves the finished entry into place and evicts down to capacity.
+    pub async fn commit(mut self) -> io::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.flush().await?;
+            file.sync_all().await?;
+        }
+
+        tokio::fs::rename(&self.temp_path, self.cache.inner.directory.join(&self.name)).await?;
+        self.file = None;
+
+        self.cache
+            .inner
+            .index
+            .lock()
+            .unwrap()
+            .insert(self.name.clone(), self.size);
+
+        self.cache.evict().await;
+
+        Ok(())
+    }
+}
+
+impl Drop for CacheWriter {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
@@ -0,0 +1,423 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io::{self, SeekFrom},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::body::Bytes;
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
+use http::{header::CONTENT_LENGTH, HeaderMap, StatusCode};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::watch,
+};
+
+use crate::disk_cache::DiskCache;
+
+/// Largest chunk read at once when following a download through its cache file
+const READ_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Bytes of image bodies held in memory across all downloads shared without a disk cache.
+/// Bodies that do not fit are handed to a single requester instead.
+const MEMORY_BUDGET: u64 = 64 * 1024 * 1024;
+
+/// Upstream downloads currently in flight, keyed by path, so concurrent requests for the
+/// same image share a single upstream request.
+#[derive(Clone, Default)]
+pub struct Downloads {
+    in_flight: Arc<Mutex<HashMap<String, Arc<Download>>>>,
+    /// Bytes reserved against [`MEMORY_BUDGET`]
+    buffered: Arc<AtomicU64>,
+}
+
+/// Status and headers of an upstream response
+#[derive(Clone)]
+pub struct Head {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+}
+
+/// The temporary cache file a body is being written to
+#[derive(Clone)]
+struct BodyFile {
+    path: PathBuf,
+    offset: u64,
+}
+
+/// How requests joining a download get its body
+enum Body {
+    /// Followed through the partially written cache file
+    File(BodyFile),
+    /// Kept in memory as it arrives, for bodies within the memory budget
+    Memory(Vec<Bytes>),
+    /// Neither cached nor small enough to keep, so the first requester takes the response
+    /// and the rest make their own
+    Unshared(Option<reqwest::Response>),
+}
+
+#[derive(Default)]
+struct Progress {
+    head: Option<Head>,
+    /// `None` while waiting for the head, and for unsuccessful responses
+    body: Option<Body>,
+    /// Body bytes written to the cache file so far
+    written: u64,
+    finished: Option<Result<(), String>>,
+    reservation: Option<Reservation>,
+}
+
+/// Bytes reserved against the memory budget, returned when dropped.
+struct Reservation {
+    buffered: Arc<AtomicU64>,
+    len: u64,
+}
+
+/// A single upstream download, written to the disk cache or, when small, kept in memory as it
+/// arrives, so every request that joins it can read it from the start.
+pub struct Download {
+    progress: Mutex<Progress>,
+    changed: watch::Sender<()>,
+}
+
+/// Where a request following a download has got to
+enum Position {
+    File { file: File, offset: u64 },
+    Memory { index: usize },
+}
+
+/// A request following a download, ended by dropping it.
+struct Follower {
+    download: Arc<Download>,
+    changed: watch::Receiver<()>,
+    position: Position,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.buffered.fetch_sub(self.len, Ordering::Relaxed);
+    }
+}
+
+impl Downloads {
+    /// Joins the download of `key`, starting it with `start` if nothing is in flight.
+    ///
+    /// The download runs in its own task and continues even if every requester goes away,
+    /// copying complete (200) responses into `cache` as the body arrives, with `metadata`
+    /// describing the response head stored alongside.
+    pub fn join<F>(
+        &self,
+        key: &str,
+        start: F,
+        cache: Option<DiskCache>,
+        metadata: fn(&Head) -> Vec<u8>,
+    ) -> Arc<Download>
+    where
+        F: Future<Output = anyhow::Result<reqwest::Response>> + Send + 'static,
+    {
+        let mut in_flight = self.in_flight.lock().unwrap();
+
+        if let Some(download) = in_flight.get(key) {
+            tracing::debug!(key, "joining in-flight download");
+            return download.clone();
+        }
+
+        let (changed, _) = watch::channel(());
+
+        let download = Arc::new(Download {
+            progress: Mutex::new(Progress::default()),
+            changed,
+        });
+
+        in_flight.insert(key.to_string(), download.clone());
+
+        tokio::spawn({
+            let downloads = self.clone();
+            let download = download.clone();
+            let key = key.to_string();
+
+            async move {
+                let result = download.run(&key, start, cache, metadata, &downloads).await;
+
+                if let Err(error) = &result {
+                    tracing::warn!(
+                        key,
+                        error = format!("{error:#}"),
+                        "upstream download failed"
+                    );
+                }
+
+                download.update(|progress| {
+                    progress.finished = Some(result.map_err(|error| format!("{error:#}")))
+                });
+
+                let mut in_flight = downloads.in_flight.lock().unwrap();
+
+                if in_flight
+                    .get(&key)
+                    .is_some_and(|current| Arc::ptr_eq(current, &download))
+                {
+                    in_flight.remove(&key);
+                }
+            }
+        });
+
+        download
+    }
+
+    /// Reserves `len` bytes of the memory budget, `None` when they do not fit.
+    fn reserve(&self, len: u64) -> Option<Reservation> {
+        self.buffered
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |buffered| {
+                (buffered + len <= MEMORY_BUDGET).then_some(buffered + len)
+            })
+            .ok()?;
+
+        Some(Reservation {
+            buffered: self.buffered.clone(),
+            len,
+        })
+    }
+}
+
+impl Download {
+    fn update(&self, f: impl FnOnce(&mut Progress)) {
+        f(&mut self.progress.lock().unwrap());
+        self.changed.send_replace(());
+    }
+
+    async fn run<F>(
+        &self,
+        key: &str,
+        start: F,
+        cache: Option<DiskCache>,
+        metadata: fn(&Head) -> Vec<u8>,
+        downloads: &Downloads,
+    ) -> anyhow::Result<()>
+    where
+        F: Future<Output = anyhow::Result<reqwest::Response>>,
+    {
+        let response = start.await?;
+
+        let mut head = Head {
+            status: response.status(),
+            headers: response.headers().clone(),
+        };
+
+        if head.status != StatusCode::OK {
+            // Error pages are not worth sharing, requesters get the status alone
+            head.headers.remove(CONTENT_LENGTH);
+            self.update(|progress| progress.head = Some(head));
+
+            return Ok(());
+        }
+
+        let writer = match cache {
+            Some(cache) => match cache.writer(key, &metadata(&head)).await {
+                Ok(writer) => Some(writer),
+                Err(error) => {
+                    tracing::warn!(key, %error, "failed to create image cache entry");
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let Some(mut writer) = writer else {
+            let reservation = response
+                .content_length()
+                .and_then(|len| downloads.reserve(len));
+
+            let Some(reservation) = reservation else {
+                self.update(|progress| {
+                    progress.head = Some(head);
+                    progress.body = Some(Body::Unshared(Some(response)));
+                });
+
+                return Ok(());
+            };
+
+            self.update(|progress| {
+                progress.head = Some(head);
+                progress.body = Some(Body::Memory(Vec::new()));
+                progress.reservation = Some(reservation);
+            });
+
+            let mut stream = response.bytes_stream();
+
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+
+                self.update(|progress| {
+                    if let Some(Body::Memory(chunks)) = &mut progress.body {
+                        chunks.push(chunk);
+                    }
+                });
+            }
+
+            return Ok(());
+        };
+
+        let body = BodyFile {
+            path: writer.temp_path().to_path_buf(),
+            offset: writer.body_offset(),
+        };
+
+        self.update(|progress| {
+            progress.head = Some(head);
+            progress.body = Some(Body::File(body));
+        });
+
+        let mut stream = response.bytes_stream();
+
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+
+            writer.write(&chunk).await?;
+
+            self.update(|progress| progress.written += chunk.len() as u64);
+        }
+
+        writer.commit().await?;
+
+        Ok(())
+    }
+
+    /// Waits for the upstream status and headers, then follows the body as it arrives.
+    ///
+    /// Returns `None` when the body cannot be followed, because another requester took an
+    /// unshared response, or the cache file was already committed or discarded. The requester
+    /// should then read the cache entry or make its own upstream request.
+    pub async fn follow(
+        self: Arc<Self>,
+    ) -> anyhow::Result<Option<(Head, BoxStream<'static, io::Result<Bytes>>)>> {
+        let mut changed = self.changed.subscribe();
+
+        let (head, position) = loop {
+            changed.borrow_and_update();
+
+            {
+                let mut progress = self.progress.lock().unwrap();
+
+                if let Some(head) = progress.head.clone() {
+                    let position = match &mut progress.body {
+                        Some(Body::File(body)) => Some(body.clone()),
+                        Some(Body::Memory(_)) => None,
+                        Some(Body::Unshared(response)) => {
+                            let Some(response) = response.take() else {
+                                return Ok(None);
+                            };
+
+                            let body = response.bytes_stream().map_err(io::Error::other);
+
+                            return Ok(Some((head, body.boxed())));
+                        }
+                        None => return Ok(Some((head, futures::stream::empty().boxed()))),
+                    };
+
+                    break (head, position);
+                }
+
+                if let Some(Err(error)) = &progress.finished {
+                    anyhow::bail!("{error}");
+                }
+            }
+
+            changed.changed().await?;
+        };
+
+        let position = match position {
+            Some(body) => match File::open(&body.path).await {
+                Ok(mut file) => {
+                    file.seek(SeekFrom::Start(body.offset)).await?;
+                    Position::File { file, offset: 0 }
+                }
+                Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(error) => return Err(error.into()),
+            },
+            None => Position::Memory { index: 0 },
+        };
+
+        let follower = Follower {
+            download: self,
+            changed,
+            position,
+        };
+
+        Ok(Some((
+            head,
+            futures::stream::unfold(Some(follower), Follower::next).boxed(),
+        )))
+    }
+}
+
+impl Follower {
+    /// Reads what the download has received past this follower's position, waiting for more
+    /// until it finishes.
+    async fn next(follower: Option<Self>) -> Option<(io::Result<Bytes>, Option<Self>)> {
+        let mut follower = follower?;
+
+        loop {
+            follower.changed.borrow_and_update();
+
+            let (available, finished) = {
+                let progress = follower.download.progress.lock().unwrap();
+
+                let available = match (&follower.position, &progress.body) {
+                    (Position::File { offset, .. }, _) => {
+                        Available::File(progress.written.saturating_sub(*offset))
+                    }
+                    (Position::Memory { index }, Some(Body::Memory(chunks))) => {
+                        Available::Memory(chunks.get(*index).cloned())
+                    }
+                    _ => Available::Memory(None),
+                };
+
+                (available, progress.finished.clone())
+            };
+
+            match (&mut follower.position, available) {
+                (Position::File { file, offset }, Available::File(len)) if len > 0 => {
+                    let len = len.min(READ_CHUNK_SIZE);
+                    let mut chunk = vec![0; len as usize];
+
+                    if let Err(error) = file.read_exact(&mut chunk).await {
+                        return Some((Err(error), None));
+                    }
+
+                    *offset += len;
+
+                    return Some((Ok(chunk.into()), Some(follower)));
+                }
+                (Position::Memory { index }, Available::Memory(Some(chunk))) => {
+                    *index += 1;
+
+                    return Some((Ok(chunk), Some(follower)));
+                }
+                _ => {}
+            }
+
+            match finished {
+                Some(Ok(())) => return None,
+                Some(Err(error)) => return Some((Err(io::Error::other(error)), None)),
+                None => {
+                    if follower.changed.changed().await.is_err() {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Body a follower can read next, taken while the progress is locked
+enum Available {
+    /// Bytes written to the cache file past the follower's offset
+    File(u64),
+    Memory(Option<Bytes>),
+}
@@ -1,43 +1,54 @@
-use std::{env, sync::Arc};
+use std::sync::Arc;
 
 use askama::Template;
 use axum::{
     extract::{Host, OriginalUri, Path, Query, State},
     headers::{CacheControl, UserAgent},
-    middleware,
     response::{Html, IntoResponse, Redirect, Response},
     routing::get,
     Router, TypedHeader,
 };
 use http::Uri;
 use serde::Deserialize;
-use tokio::sync::RwLock;
 
 use crate::{
+    config::Config,
     helper::PhixivError,
-    pixiv::{ArtworkListing, ArtworkPath, RawArtworkPath},
-    state::{authorized_middleware, PhixivState},
+    pixiv::{
+        ArtworkListing, ArtworkPath, NovelListing, RawArtworkPath, RawNovelPath, RawUserPath,
+        UserListing,
+    },
+    state::PhixivState,
 };
 
 async fn artwork_response(
     raw_path: RawArtworkPath,
-    state: Arc<RwLock<PhixivState>>,
+    state: Arc<PhixivState>,
     host: String,
 ) -> anyhow::Result<Response> {
     let path: ArtworkPath = raw_path.try_into()?;
 
-    let state = state.read().await;
+    let account = state.accounts.lease()?;
 
-    let listing = ArtworkListing::get_listing(
-        path.language,
-        path.id,
-        &state.auth.access_token,
-        &host,
-        &state.client,
-    )
-    .await?;
+    let listing = account
+        .track(|access_token| {
+            ArtworkListing::get_listing(
+                path.language.clone(),
+                path.id.clone(),
+                access_token,
+                &host,
+                &state.upstream,
+                &state.listings,
+                &state.signer,
+            )
+        })
+        .await?;
+
+    let policy = state.config.sensitive_policy(&host);
+
+    policy.check(listing.restriction)?;
 
-    let artwork = listing.to_template(path.image_index, host);
+    let artwork = listing.to_template(path.image_index, &state.config, &state.signer, host);
 
     Ok((
         TypedHeader(CacheControl::new().with_no_cache()),
@@ -48,15 +59,19 @@ async fn artwork_response(
 
 async fn artwork_handler(
     Path(path): Path<RawArtworkPath>,
-    State(state): State<Arc<RwLock<PhixivState>>>,
+    State(state): State<Arc<PhixivState>>,
     TypedHeader(user_agent): TypedHeader<UserAgent>,
     Host(host): Host,
-) -> Result<Response, PhixivError> {
-    if let Some(resp) = filter_bots(user_agent, &path) {
-        return Ok(resp);
+) -> Response {
+    let redirect_uri = pixiv_uri(&path.language, &format!("artworks/{}", path.id));
+
+    if let Some(resp) = filter_bots(&state.config, user_agent, &redirect_uri) {
+        return resp;
     }
 
-    Ok(artwork_response(path, state, host).await?)
+    artwork_response(path, state, host)
+        .await
+        .unwrap_or_else(|error| error_embed(error.into(), redirect_uri))
 }
 
 #[derive(Deserialize)]
@@ -76,44 +91,198 @@ impl From<MemberIllustParams> for RawArtworkPath {
 
 async fn member_illust_handler(
     Query(params): Query<MemberIllustParams>,
-    State(state): State<Arc<RwLock<PhixivState>>>,
+    State(state): State<Arc<PhixivState>>,
     TypedHeader(user_agent): TypedHeader<UserAgent>,
     Host(host): Host,
-) -> Result<Response, PhixivError> {
+) -> Response {
     let raw_path: RawArtworkPath = params.into();
 
-    if let Some(resp) = filter_bots(user_agent, &raw_path) {
-        return Ok(resp);
+    let redirect_uri = pixiv_uri(&raw_path.language, &format!("artworks/{}", raw_path.id));
+
+    if let Some(resp) = filter_bots(&state.config, user_agent, &redirect_uri) {
+        return resp;
+    }
+
+    artwork_response(raw_path, state, host)
+        .await
+        .unwrap_or_else(|error| error_embed(error.into(), redirect_uri))
+}
+
+async fn novel_response(
+    path: RawNovelPath,
+    state: Arc<PhixivState>,
+    host: String,
+) -> anyhow::Result<Response> {
+    let account = state.accounts.lease()?;
+
+    let listing = account
+        .track(|access_token| {
+            NovelListing::get_listing(
+                path.language.clone(),
+                path.id.clone(),
+                access_token,
+                &host,
+                &state.upstream,
+                &state.signer,
+            )
+        })
+        .await?;
+
+    let policy = state.config.sensitive_policy(&host);
+
+    policy.check(listing.restriction)?;
+
+    let novel = listing.to_template(policy, host);
+
+    Ok((
+        TypedHeader(CacheControl::new().with_no_cache()),
+        Html(novel.render()?),
+    )
+        .into_response())
+}
+
+async fn novel_handler(
+    Path(path): Path<RawNovelPath>,
+    State(state): State<Arc<PhixivState>>,
+    TypedHeader(user_agent): TypedHeader<UserAgent>,
+    Host(host): Host,
+) -> Response {
+    let redirect_uri = pixiv_uri(&path.language, &format!("novel/show.php?id={}", path.id));
+
+    if let Some(resp) = filter_bots(&state.config, user_agent, &redirect_uri) {
+        return resp;
+    }
+
+    novel_response(path, state, host)
+        .await
+        .unwrap_or_else(|error| error_embed(error.into(), redirect_uri))
+}
+
+#[derive(Deserialize)]
+struct NovelShowParams {
+    pub id: String,
+}
+
+async fn novel_show_handler(
+    language: Option<Path<String>>,
+    Query(params): Query<NovelShowParams>,
+    State(state): State<Arc<PhixivState>>,
+    TypedHeader(user_agent): TypedHeader<UserAgent>,
+    Host(host): Host,
+) -> Response {
+    let path = RawNovelPath {
+        language: language.map(|Path(language)| language),
+        id: params.id,
+    };
+
+    let redirect_uri = pixiv_uri(&path.language, &format!("novel/show.php?id={}", path.id));
+
+    if let Some(resp) = filter_bots(&state.config, user_agent, &redirect_uri) {
+        return resp;
     }
 
-    Ok(artwork_response(raw_path, state, host).await?)
+    novel_response(path, state, host)
+        .await
+        .unwrap_or_else(|error| error_embed(error.into(), redirect_uri))
 }
 
-fn filter_bots(user_agent: UserAgent, raw_path: &RawArtworkPath) -> Option<Response> {
-    if env::var("BOT_FILTERING")
-        .unwrap_or_else(|_| String::from("false"))
-        .parse::<bool>()
-        .ok()?
-    {
+async fn user_response(
+    path: RawUserPath,
+    state: Arc<PhixivState>,
+    host: String,
+) -> anyhow::Result<Response> {
+    let account = state.accounts.lease()?;
+
+    let listing = account
+        .track(|access_token| {
+            UserListing::get_listing(
+                path.id.clone(),
+                access_token,
+                &host,
+                &state.upstream,
+                &state.signer,
+            )
+        })
+        .await?;
+
+    let user = listing.to_template(state.config.sensitive_policy(&host), host);
+
+    Ok((
+        TypedHeader(CacheControl::new().with_no_cache()),
+        Html(user.render()?),
+    )
+        .into_response())
+}
+
+async fn user_handler(
+    Path(path): Path<RawUserPath>,
+    State(state): State<Arc<PhixivState>>,
+    TypedHeader(user_agent): TypedHeader<UserAgent>,
+    Host(host): Host,
+) -> Response {
+    let redirect_uri = pixiv_uri(&path.language, &format!("users/{}", path.id));
+
+    if let Some(resp) = filter_bots(&state.config, user_agent, &redirect_uri) {
+        return resp;
+    }
+
+    user_response(path, state, host)
+        .await
+        .unwrap_or_else(|error| error_embed(error.into(), redirect_uri))
+}
+
+#[derive(Template)]
+#[template(path = "error.html")]
+struct ErrorTemplate {
+    title: &'static str,
+    description: String,
+    url: String,
+}
+
+/// Renders a failed lookup as a small embed explaining why, linking to the work on pixiv.
+///
+/// Served as 200, since crawlers do not unfurl other statuses, and never cached so the embed
+/// recovers once the error does.
+fn error_embed(error: PhixivError, url: String) -> Response {
+    let title = error.title();
+    let (_, description) = error.into_parts();
+
+    let template = ErrorTemplate {
+        title,
+        description,
+        url,
+    };
+
+    match template.render() {
+        Ok(html) => (TypedHeader(CacheControl::new().with_no_cache()), Html(html)).into_response(),
+        Err(error) => PhixivError::from(error).into_response(),
+    }
+}
+
+fn filter_bots(config: &Config, user_agent: UserAgent, redirect_uri: &str) -> Option<Response> {
+    if config.bot_filtering {
         let bots = isbot::Bots::default();
 
         if !bots.is_bot(user_agent.as_str()) {
-            let redirect_uri = format!(
-                "https://www.pixiv.net{}/artworks/{}",
-                raw_path
-                    .language
-                    .as_ref()
-                    .map(|l| format!("/{l}"))
-                    .unwrap_or_else(|| String::from("")),
-                raw_path.id
-            );
-            return Some(Redirect::temporary(&redirect_uri).into_response());
+            return Some(Redirect::temporary(redirect_uri).into_response());
         }
     }
 
     None
 }
 
+/// Canonical pixiv url for humans skipping the embed, e.g. `/en/artworks/1234`.
+fn pixiv_uri(language: &Option<String>, path: &str) -> String {
+    format!(
+        "https://www.pixiv.net{}/{}",
+        language
+            .as_ref()
+            .map(|l| format!("/{l}"))
+            .unwrap_or_else(|| String::from("")),
+        path
+    )
+}
+
 fn redirect_uri(uri: Uri) -> String {
     let Some(path_and_query) = uri.path_and_query() else {
         return String::from("https://www.pixiv.net/");
@@ -132,15 +301,18 @@ async fn redirect_fallback(OriginalUri(uri): OriginalUri) -> Redirect {
     Redirect::temporary(&redirect_uri(uri))
 }
 
-pub fn router(
-    state: Arc<RwLock<PhixivState>>,
-) -> Router<Arc<RwLock<PhixivState>>, axum::body::Body> {
+pub fn router() -> Router<Arc<PhixivState>, axum::body::Body> {
     Router::new()
         .route("/:language/artworks/:id", get(artwork_handler))
         .route("/:language/artworks/:id/:image_index", get(artwork_handler))
         .route("/artworks/:id", get(artwork_handler))
         .route("/artworks/:id/:image_index", get(artwork_handler))
         .route("/member_illust.php", get(member_illust_handler))
+        .route("/:language/novel/show.php", get(novel_show_handler))
+        .route("/:language/novel/:id", get(novel_handler))
+        .route("/novel/:id", get(novel_handler))
+        .route("/novel/show.php", get(novel_show_handler))
+        .route("/:language/users/:id", get(user_handler))
+        .route("/users/:id", get(user_handler))
         .fallback(redirect_fallback)
-        .layer(middleware::from_fn_with_state(state, authorized_middleware))
 }
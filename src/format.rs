@@ -0,0 +1,298 @@
+use std::str::FromStr;
+
+/// Values available to embed text formats, written `{name}`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Title,
+    Author,
+    Description,
+    /// Page shown in the embed, empty for mosaics
+    Page,
+    Pages,
+    Bookmarks,
+    Views,
+    Likes,
+    Comments,
+    /// Date first posted, `YYYY-MM-DD`
+    Date,
+    /// Date last updated, `YYYY-MM-DD`
+    Updated,
+    /// Compact summary such as `❤ 1.2k · 👁 30k · 2026-10-01`
+    Stats,
+    Tags,
+    /// `AI Generated` for AI generated works, otherwise empty
+    Ai,
+    /// `R-18` or `R-18G` for restricted works, otherwise empty
+    Restriction,
+}
+
+impl FromStr for Field {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "title" => Ok(Self::Title),
+            "author" => Ok(Self::Author),
+            "description" => Ok(Self::Description),
+            "page" => Ok(Self::Page),
+            "pages" => Ok(Self::Pages),
+            "bookmarks" => Ok(Self::Bookmarks),
+            "views" => Ok(Self::Views),
+            "likes" => Ok(Self::Likes),
+            "comments" => Ok(Self::Comments),
+            "date" => Ok(Self::Date),
+            "updated" => Ok(Self::Updated),
+            "stats" => Ok(Self::Stats),
+            "tags" => Ok(Self::Tags),
+            "ai" => Ok(Self::Ai),
+            "restriction" => Ok(Self::Restriction),
+            name => anyhow::bail!("unknown placeholder {{{name}}}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Token {
+    Text(String),
+    Field(Field),
+    /// Rendered only when the field is not empty
+    Section(Field, Vec<Token>),
+}
+
+/// A section being parsed, with the tokens parsed so far.
+struct Section {
+    /// Field and name the section was opened with, `None` for the whole format
+    open: Option<(Field, String)>,
+    tokens: Vec<Token>,
+}
+
+/// A format string for embed text, e.g. `{title} by {author}`.
+///
+/// `{#name}...{/name}` is only rendered when `name` is not empty, `{{` and `}}` are literal
+/// braces and `\n` is a line break. Leading and trailing whitespace is trimmed from the result.
+#[derive(Clone, Debug)]
+pub struct TextFormat(Vec<Token>);
+
+impl FromStr for TextFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Innermost last
+        let mut sections = vec![Section {
+            open: None,
+            tokens: Vec::new(),
+        }];
+        let mut text = String::new();
+        let mut chars = s.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '\\' if chars.peek() == Some(&'n') => {
+                    chars.next();
+                    text.push('\n');
+                }
+                '{' => {
+                    let mut name = String::new();
+
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => anyhow::bail!("unclosed placeholder {{{name}"),
+                        }
+                    }
+
+                    let tokens = &mut sections.last_mut().expect("root section").tokens;
+
+                    if !text.is_empty() {
+                        tokens.push(Token::Text(std::mem::take(&mut text)));
+                    }
+
+                    if let Some(name) = name.strip_prefix('#') {
+                        sections.push(Section {
+                            open: Some((name.parse()?, name.to_string())),
+                            tokens: Vec::new(),
+                        });
+                    } else if let Some(name) = name.strip_prefix('/') {
+                        let field = name.parse()?;
+
+                        match sections.pop() {
+                            Some(Section {
+                                open: Some((open, _)),
+                                tokens,
+                            }) if open == field => sections
+                                .last_mut()
+                                .expect("root section")
+                                .tokens
+                                .push(Token::Section(field, tokens)),
+                            _ => anyhow::bail!("{{/{name}}} does not close a section"),
+                        }
+                    } else {
+                        tokens.push(Token::Field(name.parse()?));
+                    }
+                }
+                '}' => anyhow::bail!("unmatched }}, write }}}} for a literal brace"),
+                c => text.push(c),
+            }
+        }
+
+        let Section { open, mut tokens } = sections.pop().expect("root section");
+
+        if let Some((_, name)) = open {
+            anyhow::bail!("{{#{name}}} is never closed");
+        }
+
+        if !text.is_empty() {
+            tokens.push(Token::Text(text));
+        }
+
+        Ok(Self(tokens))
+    }
+}
+
+impl TextFormat {
+    pub fn render(&self, value: impl Fn(Field) -> String) -> String {
+        fn render_tokens(tokens: &[Token], value: &dyn Fn(Field) -> String, output: &mut String) {
+            for token in tokens {
+                match token {
+                    Token::Text(text) => output.push_str(text),
+                    Token::Field(field) => output.push_str(&value(*field)),
+                    Token::Section(field, tokens) => {
+                        if !value(*field).is_empty() {
+                            render_tokens(tokens, value, output);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut output = String::new();
+        render_tokens(&self.0, &value, &mut output);
+
+        output.trim().to_string()
+    }
+}
+
+/// Abbreviates a count for display, e.g. `999`, `1.2k`, `30k` or `4.5M`.
+pub fn compact(count: u64) -> String {
+    let (value, suffix) = match count {
+        0..=999 => return count.to_string(),
+        1_000..=999_999 => (count as f64 / 1_000.0, "k"),
+        _ => (count as f64 / 1_000_000.0, "M"),
+    };
+
+    // One decimal below 10, rounded down so counts are never overstated
+    if value < 10.0 {
+        let value = (value * 10.0).floor() / 10.0;
+
+        format!("{}{suffix}", value)
+    } else {
+        format!("{}{suffix}", value.floor())
+    }
+}
+
+/// Shortens `text` to at most `max_length` characters, ending it with an ellipsis when cut.
+/// A `max_length` of 0 means no limit.
+pub fn truncate(text: String, max_length: usize) -> String {
+    if max_length == 0 || text.chars().count() <= max_length {
+        return text;
+    }
+
+    let mut truncated = text
+        .chars()
+        .take(max_length.saturating_sub(1))
+        .collect::<String>()
+        .trim_end()
+        .to_string();
+
+    truncated.push('…');
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(format: &str, value: impl Fn(Field) -> String) -> String {
+        format.parse::<TextFormat>().unwrap().render(value)
+    }
+
+    fn sample(field: Field) -> String {
+        match field {
+            Field::Title => String::from("Sunset"),
+            Field::Author => String::from("Hazel"),
+            Field::Ai => String::new(),
+            _ => String::from("?"),
+        }
+    }
+
+    #[test]
+    fn renders_fields_and_escapes() {
+        assert_eq!(render("{title} by {author}", sample), "Sunset by Hazel");
+        assert_eq!(render("{{title}} }}", sample), "{title} }");
+        assert_eq!(render(r"{title}\n{author}", sample), "Sunset\nHazel");
+        assert_eq!(render(r"  {ai}\n{title}  ", sample), "Sunset");
+        assert_eq!(render("", sample), "");
+    }
+
+    #[test]
+    fn renders_sections_only_when_not_empty() {
+        assert_eq!(render("{#ai}[{ai}] {/ai}{title}", sample), "Sunset");
+        assert_eq!(
+            render("{#author}by {author}{#ai}, {ai}{/ai}{/author}", sample),
+            "by Hazel"
+        );
+        assert_eq!(
+            render("{#ai}[{ai}] {/ai}{title}", |field| match field {
+                Field::Ai => String::from("AI Generated"),
+                field => sample(field),
+            }),
+            "[AI Generated] Sunset"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_formats() {
+        for format in [
+            "{title",
+            "{unknown}",
+            "title}",
+            "{#ai}{title}",
+            "{/ai}",
+            "{#ai}{/title}",
+            "{#author}{#ai}{/author}{/ai}",
+        ] {
+            assert!(format.parse::<TextFormat>().is_err(), "{format}");
+        }
+    }
+
+    #[test]
+    fn compacts_counts() {
+        assert_eq!(compact(0), "0");
+        assert_eq!(compact(999), "999");
+        assert_eq!(compact(1_000), "1k");
+        assert_eq!(compact(1_299), "1.2k");
+        assert_eq!(compact(9_999), "9.9k");
+        assert_eq!(compact(30_500), "30k");
+        assert_eq!(compact(999_999), "999k");
+        assert_eq!(compact(4_560_000), "4.5M");
+        assert_eq!(compact(12_000_000), "12M");
+    }
+
+    #[test]
+    fn truncates_by_characters() {
+        assert_eq!(truncate(String::from("hello"), 0), "hello");
+        assert_eq!(truncate(String::from("hello"), 5), "hello");
+        assert_eq!(truncate(String::from("hello world"), 7), "hello…");
+        assert_eq!(truncate(String::from("ねこねこねこ"), 4), "ねこね…");
+        assert_eq!(truncate(String::from("hello"), 1), "…");
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::StatusCode;
+use serde_json::json;
+
+use crate::{pixiv, state::PhixivState};
+
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Probe results older than this no longer count, e.g. because the probe task is stuck
+const PROBE_MAX_AGE: Duration = Duration::from_secs(3 * 60);
+
+/// Outcome of the latest request made to check pixiv is reachable, see [`probe_upstream`].
+#[derive(Default)]
+pub struct UpstreamProbe {
+    latest: Mutex<Option<ProbeResult>>,
+}
+
+struct ProbeResult {
+    at: Instant,
+    latency: Duration,
+    error: Option<String>,
+}
+
+/// Periodically makes a cheap pixiv request with a pooled account, for the readiness check.
+pub async fn probe_upstream(state: Arc<PhixivState>) {
+    let mut interval = tokio::time::interval(PROBE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let started = Instant::now();
+
+        let result = async {
+            let account = state.accounts.lease()?;
+
+            account
+                .track(|access_token| pixiv::probe(access_token, &state.upstream))
+                .await
+        }
+        .await;
+
+        if let Err(error) = &result {
+            tracing::warn!(error = format!("{error:#}"), "upstream probe failed");
+        }
+
+        *state.probe.latest.lock().unwrap() = Some(ProbeResult {
+            at: Instant::now(),
+            latency: started.elapsed(),
+            error: result.err().map(|error| format!("{error:#}")),
+        });
+    }
+}
+
+/// Liveness, answering as long as the server is running.
+pub async fn health_handler() -> impl IntoResponse {
+    Json(json!({ "health": "UP" }))
+}
+
+/// Readiness, answering 503 unless phixiv can actually serve embeds: an account holds a valid
+/// token, the latest upstream probe succeeded, and the image cache, if any, is writable.
+pub async fn readiness_handler(State(state): State<Arc<PhixivState>>) -> Response {
+    let accounts_ready = state.accounts.available();
+
+    let (upstream_ready, upstream) = match &*state.probe.latest.lock().unwrap() {
+        Some(probe) => (
+            probe.error.is_none() && probe.at.elapsed() < PROBE_MAX_AGE,
+            json!({
+                "ok": probe.error.is_none(),
+                "checked_seconds_ago": probe.at.elapsed().as_secs(),
+                "latency_ms": probe.latency.as_millis() as u64,
+                "error": probe.error,
+            }),
+        ),
+        None => (false, json!({ "ok": false, "error": "not probed yet" })),
+    };
+
+    let (images_ready, images) = match &state.images {
+        Some(images) => {
+            let (used, capacity) = images.usage();
+            let writable = images.check_writable().await;
+
+            if let Err(error) = &writable {
+                tracing::warn!(%error, "image cache is not writable");
+            }
+
+            (
+                writable.is_ok(),
+                json!({
+                    "writable": writable.is_ok(),
+                    "used_bytes": used,
+                    "capacity_bytes": capacity,
+                }),
+            )
+        }
+        None => (true, serde_json::Value::Null),
+    };
+
+    let ready = accounts_ready && upstream_ready && images_ready;
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(json!({
+            "health": if ready { "UP" } else { "DOWN" },
+            "accounts": state.accounts.status(),
+            "upstream": upstream,
+            "caches": {
+                "images": images,
+                "renders": {
+                    "entries": state.renders.entry_count(),
+                    "bytes": state.renders.weighted_size(),
+                },
+                "listings": {
+                    "entries": state.listings.entry_count(),
+                },
+            },
+        })),
+    )
+        .into_response()
+}
@@ -1,5 +1,15 @@
+use std::{error::Error, fmt, sync::Arc, time::Duration};
+
 use axum::response::{IntoResponse, Response};
-use http::{HeaderMap, HeaderValue, StatusCode};
+use http::{header::RETRY_AFTER, HeaderMap, HeaderValue, StatusCode};
+
+use crate::{
+    auth::AuthError, pixiv::PixivError, rate_limit::Throttled, sensitive::SensitiveRefused,
+    upstream::Overloaded,
+};
+
+/// How long clients are asked to wait before retrying when phixiv is unavailable
+const UNAVAILABLE_RETRY_AFTER: Duration = Duration::from_secs(30);
 
 pub fn headers() -> HeaderMap<HeaderValue> {
     let mut headers = HeaderMap::with_capacity(5);
@@ -14,11 +24,205 @@ pub fn headers() -> HeaderMap<HeaderValue> {
     headers
 }
 
-pub struct PhixivError(anyhow::Error);
+/// Headers for requests to `i.pximg.net`, which refuses anything without a pixiv referer.
+pub fn pximg_headers(access_token: &str) -> anyhow::Result<HeaderMap<HeaderValue>> {
+    let mut headers = headers();
+    headers.append("Referer", "https://www.pixiv.net/".parse()?);
+    headers.append("Authorization", format!("Bearer {access_token}").parse()?);
+
+    Ok(headers)
+}
+
+/// A malformed request, such as an unknown image format or a non-numeric page index.
+#[derive(Debug)]
+pub struct InvalidRequest(pub String);
+
+impl fmt::Display for InvalidRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidRequest {}
+
+/// An error shared by every request waiting on the same cache load.
+#[derive(Debug)]
+pub struct SharedError(pub Arc<anyhow::Error>);
+
+impl fmt::Display for SharedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for SharedError {}
+
+/// Finds an error of type `T` in `error`'s chain, including errors shared through a cache.
+pub fn find_cause<T: Error + 'static>(error: &anyhow::Error) -> Option<&T> {
+    error.chain().find_map(|cause| match cause.downcast_ref() {
+        Some(SharedError(shared)) => find_cause(shared),
+        None => cause.downcast_ref(),
+    })
+}
+
+/// Why a request failed, each variant mapping to the status code clients see.
+#[derive(Debug)]
+pub enum PhixivError {
+    /// 400, the link itself is malformed
+    BadRequest(anyhow::Error),
+    /// 403, pixiv will not show the work to phixiv's account
+    Forbidden(anyhow::Error),
+    /// 404, the work is deleted or never existed
+    NotFound(anyhow::Error),
+    /// 429, the client is over its rate limit
+    TooManyRequests(anyhow::Error),
+    /// 502, pixiv failed or answered with something unexpected
+    BadGateway(anyhow::Error),
+    /// 503, phixiv cannot currently authenticate with pixiv, or is overloaded
+    Unavailable(anyhow::Error),
+    /// 500, anything else
+    Internal(anyhow::Error),
+}
+
+impl PhixivError {
+    /// Picks the variant for `error` from the first typed error in its chain.
+    fn variant(error: &anyhow::Error) -> fn(anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if let Some(SharedError(shared)) = cause.downcast_ref() {
+                return Self::variant(shared);
+            }
+
+            if let Some(pixiv_error) = cause.downcast_ref::<PixivError>() {
+                return match pixiv_error {
+                    PixivError::NotFound => Self::NotFound,
+                    PixivError::Restricted => Self::Forbidden,
+                    PixivError::RateLimited | PixivError::InvalidToken => Self::Unavailable,
+                    PixivError::Status(_) => Self::BadGateway,
+                };
+            }
+
+            if cause.is::<SensitiveRefused>() {
+                return Self::Forbidden;
+            }
+
+            if cause.is::<Throttled>() {
+                return Self::TooManyRequests;
+            }
+
+            if cause.is::<Overloaded>() {
+                return Self::Unavailable;
+            }
+
+            if cause.is::<InvalidRequest>() {
+                return Self::BadRequest;
+            }
+
+            if cause.is::<AuthError>() {
+                return Self::Unavailable;
+            }
+
+            if cause.is::<reqwest::Error>() {
+                return Self::BadGateway;
+            }
+        }
+
+        Self::Internal
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Short heading for the error, used as the title of error embeds.
+    pub fn title(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "Invalid link",
+            Self::Forbidden(_) => "Restricted",
+            Self::NotFound(_) => "Not found",
+            Self::TooManyRequests(_) => "Slow down",
+            Self::BadGateway(_) => "pixiv is unavailable",
+            Self::Unavailable(_) => "phixiv is unavailable",
+            Self::Internal(_) => "Something went wrong",
+        }
+    }
+
+    /// Message safe to show clients, internal details are only logged.
+    pub fn message(&self) -> String {
+        match self {
+            Self::BadRequest(error) => error.to_string(),
+            Self::Forbidden(error) => match find_cause::<SensitiveRefused>(error) {
+                Some(refused) => refused.to_string(),
+                None => String::from("pixiv does not allow this to be embedded"),
+            },
+            Self::NotFound(_) => {
+                String::from("Nothing exists at this link, it may have been deleted")
+            }
+            Self::TooManyRequests(error) => error.to_string(),
+            Self::BadGateway(_) => String::from("pixiv could not be reached, try again later"),
+            Self::Unavailable(error) => match find_cause::<Overloaded>(error) {
+                Some(_) => String::from("phixiv is too busy right now, try again shortly"),
+                None => String::from("phixiv cannot sign in to pixiv right now, try again later"),
+            },
+            Self::Internal(_) => String::from("An internal error occurred"),
+        }
+    }
+
+    /// `Retry-After` for errors clients should retry later.
+    pub fn headers(&self) -> HeaderMap {
+        let retry_after = match self {
+            Self::TooManyRequests(error) => {
+                find_cause::<Throttled>(error).map(|Throttled(wait)| *wait)
+            }
+            Self::Unavailable(_) => Some(UNAVAILABLE_RETRY_AFTER),
+            _ => None,
+        };
+
+        let mut headers = HeaderMap::new();
+
+        if let Some(retry_after) = retry_after {
+            // Rounded up, so clients never retry before a token is available
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+
+            headers.insert(RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+        }
+
+        headers
+    }
+
+    /// Logs server side failures, returning the status and message to respond with.
+    pub fn into_parts(self) -> (StatusCode, String) {
+        let status = self.status();
+
+        if status.is_server_error() {
+            let (Self::BadRequest(error)
+            | Self::Forbidden(error)
+            | Self::NotFound(error)
+            | Self::TooManyRequests(error)
+            | Self::BadGateway(error)
+            | Self::Unavailable(error)
+            | Self::Internal(error)) = &self;
+
+            tracing::error!(%status, error = format!("{error:#}"), "request failed");
+        }
+
+        (status, self.message())
+    }
+}
 
 impl IntoResponse for PhixivError {
     fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", self.0)).into_response()
+        let headers = self.headers();
+        let (status, message) = self.into_parts();
+
+        (status, headers, message).into_response()
     }
 }
 
@@ -27,6 +231,8 @@ where
     E: Into<anyhow::Error>,
 {
     fn from(value: E) -> Self {
-        Self(value.into())
+        let error = value.into();
+
+        Self::variant(&error)(error)
     }
 }
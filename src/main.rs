@@ -1,21 +1,38 @@
+pub mod accounts;
 pub mod api;
 pub mod auth;
+pub mod config;
+pub mod disk_cache;
+pub mod download;
 pub mod embed;
+pub mod format;
+pub mod health;
 pub mod helper;
+pub mod metrics;
+pub mod mosaic;
 pub mod oembed;
 pub mod pixiv;
 pub mod proxy;
+pub mod rate_limit;
+pub mod sensitive;
+pub mod signature;
 pub mod state;
-
-use std::{env, net::SocketAddr, sync::Arc};
-
-use api::api_router;
-use axum::{response::IntoResponse, routing::get, Json, Router};
+pub mod token_store;
+pub mod transform;
+pub mod ugoira;
+pub mod upstream;
+
+use std::{net::SocketAddr, sync::Arc};
+
+use api::{activity_handler, api_router};
+use axum::{middleware, routing::get, Router};
+use config::Config;
+use health::{health_handler, readiness_handler};
+use metrics::{metrics_handler, metrics_middleware};
 use oembed::oembed_handler;
 use proxy::proxy_router;
-use serde_json::json;
+use rate_limit::rate_limit_middleware;
 use state::PhixivState;
-use tokio::sync::RwLock;
 use tower_http::{
     normalize_path::NormalizePathLayer,
     trace::{DefaultMakeSpan, DefaultOnResponse, TraceLayer},
@@ -29,23 +46,18 @@ use tracing_subscriber::{
 async fn main() -> anyhow::Result<()> {
     dotenvy::dotenv().ok();
 
-    let addr: SocketAddr = format!(
-        "[::]:{}",
-        env::var("PORT").unwrap_or_else(|_| String::from("3000"))
-    )
-    .parse()?;
+    let config = Config::load()?;
+
+    let addr: SocketAddr = format!("[::]:{}", config.port).parse()?;
 
     let tracing_registry = tracing_subscriber::registry()
         .with(fmt::layer())
         .with(EnvFilter::from_default_env());
 
-    if let Ok(loki_url) = env::var("LOKI_URL") {
+    if let Some(loki_url) = &config.loki_url {
         let (layer, task) = tracing_loki::builder()
-            .label(
-                "environment",
-                env::var("ENVIRONMENT").unwrap_or_else(|_| String::from("development")),
-            )?
-            .build_url(url::Url::parse(&loki_url).unwrap())?;
+            .label("environment", config.environment.clone())?
+            .build_url(loki_url.clone())?;
 
         tokio::spawn(task);
 
@@ -56,25 +68,34 @@ async fn main() -> anyhow::Result<()> {
 
     tracing::info!("Listening on: {addr}");
 
-    let state = Arc::new(RwLock::new(
-        PhixivState::login(env::var("PIXIV_REFRESH_TOKEN")?).await?,
-    ));
+    let state = Arc::new(PhixivState::login(config).await?);
+
+    tokio::spawn(health::probe_upstream(state.clone()));
 
     axum::Server::bind(&addr)
-        .serve(app(state).into_make_service())
+        .serve(app(state).into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal())
         .await?;
 
     Ok(())
 }
 
-fn app(state: Arc<RwLock<PhixivState>>) -> Router {
+fn app(state: Arc<PhixivState>) -> Router {
     Router::new()
-        .merge(embed::router(state.clone()))
-        .route("/health", get(health))
+        .merge(embed::router())
+        .route("/health", get(health_handler))
+        .route("/health/live", get(health_handler))
+        .route("/health/ready", get(readiness_handler))
+        .route("/metrics", get(metrics_handler))
         .route("/e", get(oembed_handler))
+        .route("/users/:id/statuses/:status_id", get(activity_handler))
         .nest("/i", proxy_router(state.clone()))
-        .nest("/api", api_router(state.clone()))
+        .nest("/api", api_router())
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit_middleware,
+        ))
+        .layer(middleware::from_fn(metrics_middleware))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
@@ -107,7 +128,3 @@ async fn shutdown_signal() {
         _ = terminate => {},
     }
 }
-
-async fn health() -> impl IntoResponse {
-    Json(json!({ "health": "UP" }))
-}
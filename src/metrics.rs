@@ -0,0 +1,196 @@
+use std::{sync::LazyLock, time::Instant};
+
+use axum::{
+    body::{self, HttpBody},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{header, Request};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use reqwest::RequestBuilder;
+
+/// Process-wide metrics, exposed in the Prometheus text format on `/metrics`
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    /// Requests served, by route and status
+    pub requests: IntCounterVec,
+    pub request_duration: HistogramVec,
+    /// Requests made to pixiv, by endpoint
+    pub upstream_duration: HistogramVec,
+    /// Failed requests to pixiv, by endpoint and status, or `network`
+    pub upstream_errors: IntCounterVec,
+    /// Access token refreshes, by result
+    pub token_refreshes: IntCounterVec,
+    /// Body bytes sent by the `/i` proxy
+    pub proxied_bytes: IntCounter,
+    /// Cache lookups, by cache and result
+    pub cache_lookups: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some(String::from("phixiv")), None)
+            .expect("valid registry prefix");
+
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "Requests served"),
+            &["route", "status"],
+        )
+        .unwrap();
+
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("request_duration_seconds", "Time to respond to requests"),
+            &["route"],
+        )
+        .unwrap();
+
+        let upstream_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_duration_seconds",
+                "Time for pixiv to answer requests",
+            ),
+            &["endpoint"],
+        )
+        .unwrap();
+
+        let upstream_errors = IntCounterVec::new(
+            Opts::new("upstream_errors_total", "Failed requests to pixiv"),
+            &["endpoint", "status"],
+        )
+        .unwrap();
+
+        let token_refreshes = IntCounterVec::new(
+            Opts::new("token_refreshes_total", "Pixiv access token refreshes"),
+            &["result"],
+        )
+        .unwrap();
+
+        let proxied_bytes =
+            IntCounter::new("proxied_bytes_total", "Body bytes sent by the image proxy").unwrap();
+
+        let cache_lookups = IntCounterVec::new(
+            Opts::new("cache_lookups_total", "Cache lookups"),
+            &["cache", "result"],
+        )
+        .unwrap();
+
+        for collector in [
+            Box::new(requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(request_duration.clone()),
+            Box::new(upstream_duration.clone()),
+            Box::new(upstream_errors.clone()),
+            Box::new(token_refreshes.clone()),
+            Box::new(proxied_bytes.clone()),
+            Box::new(cache_lookups.clone()),
+        ] {
+            registry.register(collector).unwrap();
+        }
+
+        Self {
+            registry,
+            requests,
+            request_duration,
+            upstream_duration,
+            upstream_errors,
+            token_refreshes,
+            proxied_bytes,
+            cache_lookups,
+        }
+    }
+
+    /// Counts a cache lookup as a hit or a miss.
+    pub fn cache_lookup(&self, cache: &str, hit: bool) {
+        self.cache_lookups
+            .with_label_values(&[cache, if hit { "hit" } else { "miss" }])
+            .inc();
+    }
+}
+
+/// Sends a request to pixiv, recording its latency and any failure under `endpoint`.
+pub async fn upstream(
+    endpoint: &'static str,
+    request: RequestBuilder,
+) -> reqwest::Result<reqwest::Response> {
+    let timer = METRICS
+        .upstream_duration
+        .with_label_values(&[endpoint])
+        .start_timer();
+
+    let result = request.send().await;
+
+    timer.observe_duration();
+
+    let failure = match &result {
+        Ok(response)
+            if response.status().is_client_error() || response.status().is_server_error() =>
+        {
+            Some(response.status().as_u16().to_string())
+        }
+        Ok(_) => None,
+        Err(_) => Some(String::from("network")),
+    };
+
+    if let Some(status) = failure {
+        METRICS
+            .upstream_errors
+            .with_label_values(&[endpoint, &status])
+            .inc();
+    }
+
+    result
+}
+
+/// Route label for a request path, grouping every embed page together.
+fn route(path: &str) -> &'static str {
+    match path.split('/').nth(1).unwrap_or_default() {
+        "i" => "proxy",
+        "api" => "api",
+        "e" => "oembed",
+        "health" => "health",
+        "metrics" => "metrics",
+        _ => "embed",
+    }
+}
+
+/// Records the count and latency of every request, and the bytes sent by the image proxy.
+pub async fn metrics_middleware<B>(request: Request<B>, next: Next<B>) -> Response {
+    let route = route(request.uri().path());
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    METRICS
+        .request_duration
+        .with_label_values(&[route])
+        .observe(started.elapsed().as_secs_f64());
+
+    METRICS
+        .requests
+        .with_label_values(&[route, response.status().as_str()])
+        .inc();
+
+    if route != "proxy" {
+        return response;
+    }
+
+    response.map(|body| {
+        body::boxed(body.map_data(|chunk| {
+            METRICS.proxied_bytes.inc_by(chunk.len() as u64);
+            chunk
+        }))
+    })
+}
+
+pub async fn metrics_handler() -> Response {
+    let mut buffer = Vec::new();
+
+    if let Err(error) = TextEncoder::new().encode(&METRICS.registry.gather(), &mut buffer) {
+        tracing::error!(%error, "failed to encode metrics");
+    }
+
+    ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], buffer).into_response()
+}
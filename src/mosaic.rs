@@ -0,0 +1,149 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    response::Response,
+};
+use futures::future::try_join_all;
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, Rgb, RgbImage};
+
+use crate::{
+    helper::{self, PhixivError},
+    pixiv::{self, ImageSize},
+    state::PhixivState,
+    upstream::Upstream,
+};
+
+/// Width of the composed mosaic, matching pixiv's 1200px master images
+const MOSAIC_WIDTH: u32 = 1200;
+
+const JPEG_QUALITY: u8 = 85;
+
+/// Renders the first few pages of an artwork as a single grid, `file` is the illust id followed by `.jpg`.
+pub async fn mosaic_handler(
+    State(state): State<Arc<PhixivState>>,
+    Path(file): Path<String>,
+) -> Result<Response, PhixivError> {
+    state
+        .render_response(
+            "mosaic",
+            &file,
+            "jpg",
+            "image/jpeg",
+            |illust_id, access_token| {
+                render_mosaic(
+                    illust_id,
+                    access_token,
+                    state.config.mosaic_pages,
+                    state.upstream.clone(),
+                )
+            },
+        )
+        .await
+}
+
+async fn render_mosaic(
+    illust_id: String,
+    access_token: String,
+    pages: usize,
+    upstream: Upstream,
+) -> anyhow::Result<Bytes> {
+    let page_urls = pixiv::page_urls(&illust_id, &access_token, &upstream).await?;
+
+    let pages = try_join_all(page_urls.iter().take(pages).map(|url| {
+        let upstream = &upstream;
+        let access_token = &access_token;
+
+        async move {
+            let response = upstream
+                .send(
+                    "pximg",
+                    upstream
+                        .client()
+                        .get(url)
+                        .headers(helper::pximg_headers(access_token)?),
+                )
+                .await?;
+
+            Ok::<_, anyhow::Error>(pixiv::check_status(response).await?.bytes().await?)
+        }
+    }))
+    .await?;
+
+    let mosaic = tokio::task::spawn_blocking(move || compose(pages)).await??;
+
+    Ok(mosaic.into())
+}
+
+/// Grid of a mosaic of `count` pages: columns, rows and the size of each cell.
+fn layout(first_page: ImageSize, count: u32) -> (u32, u32, ImageSize) {
+    let columns = (count.max(1) as f64).sqrt().ceil() as u32;
+    let rows = count.div_ceil(columns);
+
+    // Cells take the aspect ratio of the first page, clamped so very long pages stay legible
+    let cell_width = MOSAIC_WIDTH / columns;
+    let cell_height = (cell_width as u64 * first_page.height as u64
+        / first_page.width.max(1) as u64)
+        .clamp(cell_width as u64 / 2, cell_width as u64 * 2) as u32;
+
+    (
+        columns,
+        rows,
+        ImageSize {
+            width: cell_width,
+            height: cell_height,
+        },
+    )
+}
+
+/// Size of the mosaic of `count` pages, without rendering it.
+pub fn mosaic_size(first_page: ImageSize, count: usize) -> ImageSize {
+    let (columns, rows, cell) = layout(first_page, count as u32);
+
+    ImageSize {
+        width: cell.width * columns,
+        height: cell.height * rows,
+    }
+}
+
+/// Lays pages out in a near-square grid, each page scaled to fit its cell and centered.
+fn compose(pages: Vec<Bytes>) -> anyhow::Result<Vec<u8>> {
+    let images = pages
+        .iter()
+        .map(|page| image::load_from_memory(page))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let Some(first) = images.first() else {
+        anyhow::bail!("artwork has no pages");
+    };
+
+    let first_page = ImageSize {
+        width: first.width(),
+        height: first.height(),
+    };
+
+    let (columns, rows, cell) = layout(first_page, images.len() as u32);
+    let (cell_width, cell_height) = (cell.width, cell.height);
+
+    let mut canvas = RgbImage::from_pixel(cell_width * columns, cell_height * rows, Rgb([0, 0, 0]));
+
+    for (index, image) in images.into_iter().enumerate() {
+        let page = image
+            .resize(cell_width, cell_height, FilterType::Triangle)
+            .into_rgb8();
+
+        let column = index as u32 % columns;
+        let row = index as u32 / columns;
+
+        let x = column * cell_width + (cell_width - page.width()) / 2;
+        let y = row * cell_height + (cell_height - page.height()) / 2;
+
+        image::imageops::overlay(&mut canvas, &page, x.into(), y.into());
+    }
+
+    let mut mosaic = Vec::new();
+    JpegEncoder::new_with_quality(&mut mosaic, JPEG_QUALITY).encode_image(&canvas)?;
+
+    Ok(mosaic)
+}
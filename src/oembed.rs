@@ -1,13 +1,42 @@
-use std::env;
+use std::sync::Arc;
 
-use axum::{extract::Query, Json};
+use axum::{
+    extract::{Host, Query, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use http::{header, StatusCode};
 use serde::{Deserialize, Serialize};
+use url::Url;
 use urlencoding::encode;
 
+use crate::{
+    api::ApiError,
+    config::Config,
+    helper::{InvalidRequest, PhixivError},
+    pixiv::{ArtworkListing, ArtworkPath, ImageIndex, ImageSize, RawArtworkPath},
+    sensitive::{self, SensitivePolicy},
+    signature::UrlSigner,
+    state::PhixivState,
+    transform,
+};
+
+/// Bounding box of thumbnails, which are the photo scaled down further
+const THUMBNAIL_BOX: ImageSize = ImageSize {
+    width: 250,
+    height: 250,
+};
+
 #[derive(Deserialize)]
 pub struct EmbedRequest {
+    /// Phixiv or pixiv link to an artwork
+    pub url: Option<String>,
+    pub maxwidth: Option<u32>,
+    pub maxheight: Option<u32>,
+    pub format: Option<String>,
+    /// Author of the embed linking here, when `url` is not given
     #[serde(rename = "n")]
-    pub author_name: String,
+    pub author_name: Option<String>,
     #[serde(rename = "i")]
     pub author_id: Option<String>,
 }
@@ -17,40 +46,308 @@ pub struct EmbedResponse {
     version: &'static str,
     #[serde(rename = "type")]
     embed_type: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
     author_name: String,
     author_url: String,
     provider_name: String,
     provider_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thumbnail_height: Option<u32>,
+}
+
+/// Response formats from the oEmbed spec
+enum Format {
+    Json,
+    Xml,
+}
+
+/// An image offered as the embed's photo, `resizable` when the `/i` proxy can scale it.
+struct Photo {
+    url: String,
+    size: ImageSize,
+    resizable: bool,
 }
 
 impl EmbedResponse {
-    fn new(author_name: String, author_url: String) -> Self {
+    fn new(author_name: String, author_url: String, config: &Config) -> Self {
         Self {
             version: "1.0",
             embed_type: "rich",
+            title: None,
             author_name,
             author_url,
-            provider_name: env::var("PROVIDER_NAME").unwrap_or_else(|_| String::from("phixiv")),
-            provider_url: env::var("PROVIDER_URL").unwrap_or_else(|_| String::from("https://github.com/HazelTheWitch/phixiv")),
+            provider_name: config.provider_name.clone(),
+            provider_url: config.provider_url.to_string(),
+            url: None,
+            width: None,
+            height: None,
+            thumbnail_url: None,
+            thumbnail_width: None,
+            thumbnail_height: None,
         }
     }
+
+    /// Describes an artwork as a `photo`, scaled down to fit `bounds`. Falls back to a `link`
+    /// when no image may be shown, or when the image cannot be scaled to fit. The thumbnail is
+    /// left out when it cannot be scaled to fit within [`THUMBNAIL_BOX`].
+    fn artwork(
+        listing: ArtworkListing,
+        image_index: Option<ImageIndex>,
+        policy: SensitivePolicy,
+        bounds: ImageSize,
+        config: &Config,
+        signer: &UrlSigner,
+    ) -> Self {
+        let index = match image_index {
+            Some(ImageIndex::Page(index)) => {
+                index.min(listing.image_proxy_urls.len()).saturating_sub(1)
+            }
+            // Mosaics are sized by their pages, so the first page stands in for them
+            Some(ImageIndex::Mosaic) | None => 0,
+        };
+
+        let shown = policy.shows(listing.restriction);
+
+        let photo = match policy {
+            _ if shown => Some(Photo {
+                url: listing.image_proxy_urls[index].clone(),
+                size: listing.image_sizes[index],
+                resizable: !listing.ugoira,
+            }),
+            SensitivePolicy::Blur => listing.blur_proxy_url.clone().map(|url| Photo {
+                url,
+                size: sensitive::blur_size(listing.image_sizes[0]),
+                resizable: false,
+            }),
+            _ => None,
+        };
+
+        let title = match listing.restriction.label() {
+            Some(label) => format!("[{label}] {}", listing.title),
+            None => listing.title,
+        };
+
+        let mut response = Self::new(
+            listing.author_name,
+            format!("https://www.pixiv.net/users/{}", encode(&listing.author_id)),
+            config,
+        );
+
+        response.embed_type = "link";
+        response.title = Some(title);
+
+        let Some(photo) = photo else {
+            return response;
+        };
+
+        let size = transform::scaled_size(photo.size, bounds);
+
+        // Widths the proxy produces bottom out, so tiny bounds may not be met even when resizable
+        if (size != photo.size && !photo.resizable) || !size.within(bounds) {
+            return response;
+        }
+
+        response.embed_type = "photo";
+        response.url = Some(transform::scaled_url(
+            signer, &photo.url, size, photo.size, None,
+        ));
+        response.width = Some(size.width);
+        response.height = Some(size.height);
+
+        let thumbnail_box = size.fit(THUMBNAIL_BOX);
+        let thumbnail = transform::scaled_size(photo.size, thumbnail_box);
+
+        if photo.resizable && thumbnail.within(thumbnail_box) {
+            response.thumbnail_url = Some(transform::scaled_url(
+                signer, &photo.url, thumbnail, photo.size, None,
+            ));
+            response.thumbnail_width = Some(thumbnail.width);
+            response.thumbnail_height = Some(thumbnail.height);
+        }
+
+        response
+    }
+
+    /// Renders the response as an oEmbed XML document.
+    fn to_xml(&self) -> String {
+        let fields = match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => serde_json::Map::new(),
+        };
+
+        let body = fields
+            .into_iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    serde_json::Value::String(value) => value,
+                    value => value.to_string(),
+                };
+
+                format!("<{name}>{}</{name}>", escape_xml(&value))
+            })
+            .collect::<String>();
+
+        format!(r#"<?xml version="1.0" encoding="utf-8" standalone="yes"?><oembed>{body}</oembed>"#)
+    }
 }
 
-pub async fn oembed_handler(
-    Query(EmbedRequest {
-        author_name,
-        author_id,
-    }): Query<EmbedRequest>,
-) -> Json<EmbedResponse> {
-    if let Some(author_id) = author_id {
-        Json(EmbedResponse::new(
-            author_name,
-            format!("https://www.pixiv.net/users/{}", encode(&author_id)),
-        ))
-    } else {
-        Json(EmbedResponse::new(
-            author_name,
-            String::from("https://www.pixiv.net/"),
-        ))
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Recognises artwork links, e.g. `https://www.pixiv.net/en/artworks/1234` or the same path on
+/// any phixiv instance, including an image index.
+fn artwork_path(url: &str) -> Option<RawArtworkPath> {
+    let url = Url::parse(url).ok()?;
+
+    if !matches!(url.scheme(), "http" | "https") {
+        return None;
+    }
+
+    let segments = url
+        .path_segments()?
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+
+    let (language, id, image_index) = match segments.as_slice() {
+        ["member_illust.php"] => (
+            None,
+            url.query_pairs()
+                .find(|(key, _)| key == "illust_id")?
+                .1
+                .to_string(),
+            None,
+        ),
+        ["artworks", id, rest @ ..] if rest.len() <= 1 => (
+            None,
+            id.to_string(),
+            rest.first().map(|index| index.to_string()),
+        ),
+        [language, "artworks", id, rest @ ..] if rest.len() <= 1 => (
+            Some(language.to_string()),
+            id.to_string(),
+            rest.first().map(|index| index.to_string()),
+        ),
+        _ => return None,
+    };
+
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+        return None;
     }
+
+    Some(RawArtworkPath {
+        language,
+        id,
+        image_index,
+    })
+}
+
+async fn artwork_embed(
+    state: &PhixivState,
+    url: &str,
+    bounds: ImageSize,
+    host: String,
+) -> Result<EmbedResponse, ApiError> {
+    let Some(raw_path) = artwork_path(url) else {
+        return Err(ApiError::from(PhixivError::NotFound(anyhow::anyhow!(
+            "unsupported url: {url}"
+        ))));
+    };
+
+    let path: ArtworkPath = raw_path.try_into()?;
+
+    let account = state.accounts.lease()?;
+
+    let listing = account
+        .track(|access_token| {
+            ArtworkListing::get_listing(
+                path.language.clone(),
+                path.id.clone(),
+                access_token,
+                &host,
+                &state.upstream,
+                &state.listings,
+                &state.signer,
+            )
+        })
+        .await?;
+
+    let policy = state.config.sensitive_policy(&host);
+
+    policy.check(listing.restriction)?;
+
+    Ok(EmbedResponse::artwork(
+        listing,
+        path.image_index,
+        policy,
+        bounds,
+        &state.config,
+        &state.signer,
+    ))
+}
+
+/// oEmbed provider endpoint. With `url`, describes the linked artwork as a `photo`; otherwise
+/// echoes the author given by the embed linking here, as a `rich` response.
+pub async fn oembed_handler(
+    State(state): State<Arc<PhixivState>>,
+    Query(request): Query<EmbedRequest>,
+    Host(host): Host,
+) -> Result<Response, ApiError> {
+    let format = match request.format.as_deref() {
+        None | Some("json") => Format::Json,
+        Some("xml") => Format::Xml,
+        Some(format) => {
+            return Ok((
+                StatusCode::NOT_IMPLEMENTED,
+                format!("unsupported oEmbed format: {format}"),
+            )
+                .into_response())
+        }
+    };
+
+    let response = match (request.url, request.author_name) {
+        (Some(url), _) => {
+            let bounds = ImageSize {
+                width: request.maxwidth.unwrap_or(u32::MAX),
+                height: request.maxheight.unwrap_or(u32::MAX),
+            };
+
+            artwork_embed(&state, &url, bounds, host).await?
+        }
+        (None, Some(author_name)) => {
+            let author_url = match request.author_id {
+                Some(author_id) => format!("https://www.pixiv.net/users/{}", encode(&author_id)),
+                None => String::from("https://www.pixiv.net/"),
+            };
+
+            EmbedResponse::new(author_name, author_url, &state.config)
+        }
+        (None, None) => {
+            return Err(InvalidRequest(String::from("url is required")).into());
+        }
+    };
+
+    Ok(match format {
+        Format::Json => Json(response).into_response(),
+        Format::Xml => (
+            [(header::CONTENT_TYPE, "text/xml; charset=utf-8")],
+            response.to_xml(),
+        )
+            .into_response(),
+    })
 }
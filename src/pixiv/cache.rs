@@ -0,0 +1,81 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use moka::future::Cache;
+
+use crate::{
+    helper::{find_cause, SharedError},
+    metrics::METRICS,
+};
+
+use super::{ArtworkListing, PixivError};
+
+/// Cache key for a listing, the illust id and requested language
+pub type ListingKey = (String, Option<String>);
+
+/// Bounded TTL cache in front of pixiv, remembering listings and works pixiv reported as missing.
+#[derive(Clone)]
+pub struct ListingCache {
+    listings: Cache<ListingKey, Arc<ArtworkListing>>,
+    not_found: Cache<ListingKey, ()>,
+}
+
+impl ListingCache {
+    pub fn new(capacity: u64, time_to_live: Duration, not_found_time_to_live: Duration) -> Self {
+        Self {
+            listings: Cache::builder()
+                .max_capacity(capacity)
+                .time_to_live(time_to_live)
+                .build(),
+            not_found: Cache::builder()
+                .max_capacity(capacity)
+                .time_to_live(not_found_time_to_live)
+                .build(),
+        }
+    }
+
+    pub fn entry_count(&self) -> u64 {
+        self.listings.entry_count()
+    }
+
+    pub(super) async fn get_or_fetch(
+        &self,
+        key: ListingKey,
+        fetch: impl Future<Output = anyhow::Result<ArtworkListing>>,
+    ) -> anyhow::Result<Arc<ArtworkListing>> {
+        let (illust_id, language) = &key;
+
+        if self.not_found.contains_key(&key) {
+            tracing::info!(illust_id, ?language, "listing cache hit, not found");
+            METRICS.cache_lookup("listings", true);
+            return Err(PixivError::NotFound.into());
+        }
+
+        // Concurrent misses for the same key wait on a single fetch
+        let entry = self
+            .listings
+            .entry_by_ref(&key)
+            .or_try_insert_with(async { fetch.await.map(Arc::new) })
+            .await;
+
+        match entry {
+            Ok(entry) => {
+                if entry.is_fresh() {
+                    tracing::info!(illust_id, ?language, "listing cache miss");
+                } else {
+                    tracing::info!(illust_id, ?language, "listing cache hit");
+                }
+
+                METRICS.cache_lookup("listings", !entry.is_fresh());
+
+                Ok(entry.into_value())
+            }
+            Err(error) => {
+                if matches!(find_cause(&error), Some(PixivError::NotFound)) {
+                    self.not_found.insert(key, ()).await;
+                }
+
+                Err(SharedError(error).into())
+            }
+        }
+    }
+}
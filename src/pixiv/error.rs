@@ -0,0 +1,90 @@
+use std::fmt;
+
+use http::StatusCode;
+
+/// Failures reported by pixiv itself, as opposed to network or decoding errors.
+#[derive(Clone, Debug)]
+pub enum PixivError {
+    /// The work is deleted, private or never existed
+    NotFound,
+    /// The work exists but pixiv will not show it to phixiv's account, e.g. R-18 or mypixiv only
+    Restricted,
+    /// Pixiv is rate limiting the account that made the request
+    RateLimited,
+    /// Pixiv rejected the access token, e.g. because it was revoked before expiring
+    InvalidToken,
+    /// Pixiv answered with an unexpected status
+    Status(StatusCode),
+}
+
+impl fmt::Display for PixivError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "work not found"),
+            Self::Restricted => write!(f, "work is restricted"),
+            Self::RateLimited => write!(f, "rate limited by pixiv"),
+            Self::InvalidToken => write!(f, "pixiv rejected the access token"),
+            Self::Status(status) => write!(f, "pixiv responded with {status}"),
+        }
+    }
+}
+
+impl std::error::Error for PixivError {}
+
+impl PixivError {
+    /// Classifies an unsuccessful status from pixiv or `i.pximg.net`.
+    pub fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::NOT_FOUND => Self::NotFound,
+            StatusCode::FORBIDDEN => Self::Restricted,
+            StatusCode::TOO_MANY_REQUESTS => Self::RateLimited,
+            status => Self::Status(status),
+        }
+    }
+}
+
+/// Passes successful responses through, mapping pixiv's error statuses to [`PixivError`].
+pub async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, PixivError> {
+    let status = response.status();
+
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    // The app API reports OAuth failures as a generic 400 with the reason in the body
+    if matches!(status, StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED) {
+        let body = response.text().await.unwrap_or_default();
+
+        if is_oauth_error(&body) {
+            return Err(PixivError::InvalidToken);
+        }
+    }
+
+    Err(PixivError::from_status(status))
+}
+
+/// Passes responses through whatever their status, except those blaming the account: rate
+/// limits and rejected access tokens, which [`crate::accounts::Lease::track`] acts on.
+pub async fn check_account(response: reqwest::Response) -> Result<reqwest::Response, PixivError> {
+    let status = response.status();
+
+    match status {
+        StatusCode::TOO_MANY_REQUESTS => Err(PixivError::RateLimited),
+        StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED => {
+            let body = response.text().await.unwrap_or_default();
+
+            if is_oauth_error(&body) {
+                Err(PixivError::InvalidToken)
+            } else {
+                Err(PixivError::from_status(status))
+            }
+        }
+        _ => Ok(response),
+    }
+}
+
+/// Whether an error body is pixiv complaining about the access token, e.g.
+/// `Error occurred at the OAuth process. ... Error Message: invalid_grant`.
+fn is_oauth_error(body: &str) -> bool {
+    body.contains("invalid_grant") || body.contains("OAuth process")
+}
@@ -1,17 +1,42 @@
 use std::collections::HashMap;
 
 use askama::Template;
-use itertools::Itertools;
-use reqwest::Client;
+use http::HeaderMap;
 use serde::{Deserialize, Serialize};
 
-use crate::helper;
+use crate::{
+    config::Config,
+    format::{self, Field},
+    helper::{self, InvalidRequest},
+    mosaic,
+    sensitive::{self, SensitivePolicy},
+    signature::UrlSigner,
+    transform,
+    upstream::Upstream,
+};
 
-use self::model::{AjaxResponse, AppReponse};
+use self::model::{AjaxPagesResponse, AjaxResponse, AppReponse, Tags, UgoiraMetadataResponse};
 
+pub use self::cache::ListingCache;
+pub use self::error::{check_account, check_status, PixivError};
+pub use self::model::UgoiraFrame;
+pub use self::novel::{NovelListing, NovelTemplate, RawNovelPath};
+pub use self::user::{probe, RawUserPath, UserListing, UserTemplate};
+
+mod cache;
+mod error;
 mod model;
+mod novel;
+mod user;
 
 const ILLUST_URL: &str = "https://app-api.pixiv.net/v1/illust/detail";
+const UGOIRA_METADATA_URL: &str = "https://app-api.pixiv.net/v1/ugoira/metadata";
+
+/// Bounding box of the frames in the ugoira archives phixiv renders
+const UGOIRA_FRAME_BOX: ImageSize = ImageSize {
+    width: 600,
+    height: 600,
+};
 
 #[derive(Deserialize)]
 pub struct RawArtworkPath {
@@ -20,18 +45,32 @@ pub struct RawArtworkPath {
     pub image_index: Option<String>,
 }
 
+/// Which image of a listing to embed, a single page or a grid of the first few pages
+#[derive(Clone, Copy)]
+pub enum ImageIndex {
+    Page(usize),
+    Mosaic,
+}
+
 pub struct ArtworkPath {
     pub language: Option<String>,
     pub id: String,
-    pub image_index: Option<usize>,
+    pub image_index: Option<ImageIndex>,
 }
 
 impl TryFrom<RawArtworkPath> for ArtworkPath {
     type Error = anyhow::Error;
 
     fn try_from(value: RawArtworkPath) -> Result<Self, Self::Error> {
-        let image_index = match value.image_index {
-            Some(index) => Some(index.parse()?),
+        let image_index = match value.image_index.as_deref() {
+            Some("mosaic") => Some(ImageIndex::Mosaic),
+            Some(index) => {
+                let index = index
+                    .parse()
+                    .map_err(|_| InvalidRequest(format!("invalid image index: {index}")))?;
+
+                Some(ImageIndex::Page(index))
+            }
             None => None,
         };
 
@@ -43,10 +82,96 @@ impl TryFrom<RawArtworkPath> for ArtworkPath {
     }
 }
 
+/// Age restriction pixiv places on a work
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Restriction {
+    Safe,
+    R18,
+    R18G,
+}
+
+impl Restriction {
+    /// Maps pixiv's `x_restrict`, treating unknown levels as the strictest.
+    fn from_x_restrict(x_restrict: u8) -> Self {
+        match x_restrict {
+            0 => Self::Safe,
+            1 => Self::R18,
+            _ => Self::R18G,
+        }
+    }
+
+    /// Pixiv's name for the restriction, `None` for safe works.
+    pub fn label(self) -> Option<&'static str> {
+        match self {
+            Self::Safe => None,
+            Self::R18 => Some("R-18"),
+            Self::R18G => Some("R-18G"),
+        }
+    }
+}
+
+/// Pixel dimensions of an image
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct ImageSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ImageSize {
+    /// Scales down to fit within `bounds`, keeping the aspect ratio and never upscaling.
+    pub fn fit(self, bounds: ImageSize) -> Self {
+        if self.within(bounds) {
+            return self;
+        }
+
+        let scale = f64::min(
+            bounds.width as f64 / self.width as f64,
+            bounds.height as f64 / self.height as f64,
+        );
+
+        Self {
+            width: ((self.width as f64 * scale).round() as u32).max(1),
+            height: ((self.height as f64 * scale).round() as u32).max(1),
+        }
+    }
+
+    /// Whether neither dimension exceeds `bounds`.
+    pub fn within(self, bounds: ImageSize) -> bool {
+        self.width <= bounds.width && self.height <= bounds.height
+    }
+
+    /// Size of an image pixiv serves at `image_url`, given the `original` size. Resized images
+    /// carry their bounding box in the path, e.g. `/c/600x1200_90/img-master/...`.
+    fn served(original: ImageSize, image_url: &str) -> Self {
+        let bounds = url::Url::parse(image_url).ok().and_then(|url| {
+            let mut segments = url.path_segments()?;
+
+            segments.next().filter(|segment| *segment == "c")?;
+
+            let (width, height) = segments.next()?.split('_').next()?.split_once('x')?;
+
+            Some(ImageSize {
+                width: width.parse().ok()?,
+                height: height.parse().ok()?,
+            })
+        });
+
+        match bounds {
+            Some(bounds) => original.fit(bounds),
+            None => original,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Template)]
 #[template(path = "artwork.html")]
 pub struct ArtworkTemplate {
-    pub image_proxy_url: String,
+    pub image_proxy_url: Option<String>,
+    /// The animation again for ugoira, which some clients only play from `og:video`
+    pub video_url: Option<String>,
+    pub image_size: Option<ImageSize>,
+    pub image_type: Option<&'static str>,
     pub title: String,
     pub description: String,
     pub author_name: String,
@@ -54,157 +179,552 @@ pub struct ArtworkTemplate {
     pub url: String,
     pub alt_text: String,
     pub host: String,
+    /// Mastodon status for the whole work, offered when every page should be embedded
+    pub activity_url: Option<String>,
 }
 
-#[derive(Serialize)]
+#[derive(Clone, Serialize)]
 /// Representing a listing of artworks, uniquely determined by language and illust_id
 pub struct ArtworkListing {
+    pub illust_id: String,
     pub image_proxy_urls: Vec<String>,
+    /// Size of each image in `image_proxy_urls`
+    pub image_sizes: Vec<ImageSize>,
+    pub mosaic_proxy_url: Option<String>,
+    /// Blurred preview of the first page, only for restricted works
+    pub blur_proxy_url: Option<String>,
     pub title: String,
     pub ai_generated: bool,
+    pub restriction: Restriction,
+    pub sanity_level: u8,
     pub description: String,
     pub tags: Vec<String>,
     pub url: String,
     pub author_name: String,
     pub author_id: String,
+    pub author_account: String,
+    pub author_avatar_proxy_url: String,
+    /// ISO 8601 time the work was first posted
+    pub created_at: String,
+    /// ISO 8601 time the work was last updated
+    pub uploaded_at: String,
+    pub bookmarks: u64,
+    pub likes: u64,
+    pub views: u64,
+    pub comments: u64,
+    pub ugoira: bool,
+}
+
+/// Frame archive and timing for an ugoira, enough to reassemble the animation
+pub struct UgoiraMetadata {
+    pub zip_url: String,
+    pub frames: Vec<UgoiraFrame>,
+}
+
+fn app_headers(access_token: &str) -> anyhow::Result<HeaderMap> {
+    let mut app_headers = helper::headers();
+    app_headers.append("Host", "app-api.pixiv.net".parse()?);
+    app_headers.append("Authorization", format!("Bearer {access_token}").parse()?);
+
+    Ok(app_headers)
 }
 
 async fn app_request(
     illust_id: &String,
     access_token: &str,
-    client: &Client,
+    upstream: &Upstream,
 ) -> anyhow::Result<AppReponse> {
     let app_params = HashMap::from([("illust_id", illust_id)]);
-    let mut app_headers = helper::headers();
-    app_headers.append("Host", "app-api.pixiv.net".parse()?);
-    app_headers.append("Authorization", format!("Bearer {access_token}").parse()?);
 
-    Ok(client
-        .get(ILLUST_URL)
-        .headers(app_headers)
-        .query(&app_params)
-        .send()
-        .await?
-        .json()
-        .await?)
+    let response = upstream
+        .send(
+            "illust",
+            upstream
+                .client()
+                .get(ILLUST_URL)
+                .headers(app_headers(access_token)?)
+                .query(&app_params),
+        )
+        .await?;
+
+    let response: AppReponse = check_status(response).await?.json().await?;
+
+    if response.illust.visible == Some(false) {
+        return Err(PixivError::Restricted.into());
+    }
+
+    Ok(response)
 }
 
 async fn ajax_request(
     illust_id: &String,
     language: &Option<String>,
-    client: &Client,
+    upstream: &Upstream,
 ) -> anyhow::Result<AjaxResponse> {
-    Ok(client
-        .get(format!(
-            "https://www.pixiv.net/ajax/illust/{}?lang={}",
-            &illust_id,
-            &language.clone().unwrap_or_else(|| String::from("jp"))
-        ))
-        .send()
-        .await?
-        .json()
-        .await?)
+    let response = upstream
+        .send(
+            "illust_ajax",
+            upstream.client().get(format!(
+                "https://www.pixiv.net/ajax/illust/{}?lang={}",
+                &illust_id,
+                &language.clone().unwrap_or_else(|| String::from("jp"))
+            )),
+        )
+        .await?;
+
+    Ok(check_status(response).await?.json().await?)
+}
+
+async fn ajax_pages_request(
+    illust_id: &String,
+    upstream: &Upstream,
+) -> anyhow::Result<AjaxPagesResponse> {
+    let response = upstream
+        .send(
+            "illust_pages",
+            upstream.client().get(format!(
+                "https://www.pixiv.net/ajax/illust/{illust_id}/pages"
+            )),
+        )
+        .await?;
+
+    Ok(check_status(response).await?.json().await?)
+}
+
+pub async fn ugoira_metadata(
+    illust_id: &str,
+    access_token: &str,
+    upstream: &Upstream,
+) -> anyhow::Result<UgoiraMetadata> {
+    let app_params = HashMap::from([("illust_id", illust_id)]);
+
+    let response = upstream
+        .send(
+            "ugoira_metadata",
+            upstream
+                .client()
+                .get(UGOIRA_METADATA_URL)
+                .headers(app_headers(access_token)?)
+                .query(&app_params),
+        )
+        .await?;
+
+    let response: UgoiraMetadataResponse = check_status(response).await?.json().await?;
+
+    Ok(UgoiraMetadata {
+        zip_url: response.ugoira_metadata.zip_urls.medium,
+        frames: response.ugoira_metadata.frames,
+    })
+}
+
+/// Content type of the image at a proxy url, from its extension.
+fn image_type(url: &str) -> &'static str {
+    let path = url.split('?').next().unwrap_or_default();
+
+    if path.ends_with(".png") {
+        "image/png"
+    } else if path.ends_with(".gif") {
+        "image/gif"
+    } else {
+        "image/jpeg"
+    }
+}
+
+/// Rewrites an `i.pximg.net` url to its path on this instance's `/i` proxy. Urls on other hosts,
+/// such as the default avatar on `s.pximg.net`, are kept as they are and linked directly.
+fn proxy_path(image_url: &str) -> anyhow::Result<String> {
+    let url = url::Url::parse(image_url)?;
+
+    if url.host_str() != Some("i.pximg.net") {
+        return Ok(image_url.to_string());
+    }
+
+    Ok(format!("/i{}", url.path()))
+}
+
+/// Signs a path from [`proxy_path`] for `host`, leaving direct links alone.
+fn sign_path(host: &str, path: &str, signer: &UrlSigner) -> String {
+    if path.starts_with("/i/") {
+        signer.signed_url(host, path)
+    } else {
+        path.to_string()
+    }
+}
+
+/// Rewrites an `i.pximg.net` url to a signed url on this instance's `/i` proxy.
+fn proxy_url(host: &str, image_url: &str, signer: &UrlSigner) -> anyhow::Result<String> {
+    Ok(sign_path(host, &proxy_path(image_url)?, signer))
+}
+
+/// Formats tags as hashtags, preferring the translation for `language` when pixiv has one.
+fn hashtags(tags: Tags, language: &Option<String>) -> Vec<String> {
+    tags.tags
+        .into_iter()
+        .map(|tag| {
+            format!(
+                "#{}",
+                if let Some(language) = language {
+                    if let Some(translation) = tag.translation {
+                        translation.get(language).unwrap_or(&tag.tag).to_string()
+                    } else {
+                        tag.tag
+                    }
+                } else {
+                    tag.tag
+                }
+            )
+        })
+        .collect()
+}
+
+/// Original `i.pximg.net` urls of every page of an illustration, in order.
+pub async fn page_urls(
+    illust_id: &String,
+    access_token: &str,
+    upstream: &Upstream,
+) -> anyhow::Result<Vec<String>> {
+    let app_response = app_request(illust_id, access_token, upstream).await?;
+
+    if app_response.illust.meta_pages.is_empty() {
+        Ok(vec![app_response.illust.image_urls.large])
+    } else {
+        Ok(app_response
+            .illust
+            .meta_pages
+            .into_iter()
+            .map(|mp| mp.image_urls.large)
+            .collect())
+    }
 }
 
 impl ArtworkListing {
+    /// Looks the listing up in `cache`, fetching it from pixiv on a miss.
     pub async fn get_listing(
         language: Option<String>,
         illust_id: String,
-        access_token: &str,
+        access_token: String,
         host: &str,
-        client: &Client,
+        upstream: &Upstream,
+        cache: &ListingCache,
+        signer: &UrlSigner,
+    ) -> anyhow::Result<Self> {
+        let listing = cache
+            .get_or_fetch(
+                (illust_id.clone(), language.clone()),
+                Self::fetch(language, illust_id, &access_token, upstream),
+            )
+            .await?;
+
+        Ok(listing.with_host(host, signer))
+    }
+
+    /// Fetches a listing from pixiv, with image urls relative to the serving host.
+    async fn fetch(
+        language: Option<String>,
+        illust_id: String,
+        access_token: &str,
+        upstream: &Upstream,
     ) -> anyhow::Result<Self> {
         let (app_response, ajax_response) = tokio::try_join!(
-            app_request(&illust_id, access_token, client),
-            ajax_request(&illust_id, &language, client),
+            app_request(&illust_id, access_token, upstream),
+            ajax_request(&illust_id, &language, upstream),
         )?;
 
+        // Only later pages can differ from the size the app API reports, and their sizes are
+        // not worth failing the listing over, so pages fall back to the first page's size
+        let pages_response = if app_response.illust.meta_pages.len() > 1 {
+            ajax_pages_request(&illust_id, upstream)
+                .await
+                .unwrap_or_else(|error| {
+                    tracing::warn!(
+                        illust_id,
+                        error = format!("{error:#}"),
+                        "failed to fetch page sizes"
+                    );
+
+                    AjaxPagesResponse::default()
+                })
+        } else {
+            AjaxPagesResponse::default()
+        };
+
         let ai_generated = app_response.illust.illust_ai_type == 2;
 
-        let tags: Vec<_> = ajax_response.body
-            .tags
-            .tags
-            .into_iter()
-            .map(|tag| {
-                format!(
-                    "#{}",
-                    if let Some(language) = &language {
-                        if let Some(translation) = tag.translation {
-                            translation.get(language).unwrap_or(&tag.tag).to_string()
-                        } else {
-                            tag.tag
-                        }
-                    } else {
-                        tag.tag
-                    }
-                )
-            })
-            .collect();
+        let restriction = Restriction::from_x_restrict(app_response.illust.x_restrict);
+
+        let sanity_level = app_response.illust.sanity_level;
+
+        let tags = hashtags(ajax_response.body.tags, &language);
+
+        let ugoira = app_response.illust.illust_type == "ugoira";
 
-        let image_proxy_urls = if app_response.illust.meta_pages.is_empty() {
-            let url = url::Url::parse(&app_response.illust.image_urls.large)?;
+        let page_count = app_response.illust.meta_pages.len();
 
-            vec![format!("https://{}/i{}", host, url.path())]
+        let author_avatar_proxy_url =
+            proxy_path(&app_response.illust.user.profile_image_urls.medium)?;
+
+        let first_page = ImageSize {
+            width: app_response.illust.width,
+            height: app_response.illust.height,
+        };
+
+        let (image_proxy_urls, image_sizes) = if ugoira {
+            (
+                vec![format!("/i/ugoira/{}.gif", illust_id)],
+                vec![first_page.fit(UGOIRA_FRAME_BOX)],
+            )
+        } else if app_response.illust.meta_pages.is_empty() {
+            let image_url = app_response.illust.image_urls.large;
+
+            (
+                vec![proxy_path(&image_url)?],
+                vec![ImageSize::served(first_page, &image_url)],
+            )
         } else {
-            app_response.illust
+            app_response
+                .illust
                 .meta_pages
                 .into_iter()
-                .map(|mp| {
-                    let url = url::Url::parse(&mp.image_urls.large)?;
+                .enumerate()
+                .map(|(index, mp)| {
+                    let original = pages_response
+                        .body
+                        .get(index)
+                        .map(|page| ImageSize {
+                            width: page.width,
+                            height: page.height,
+                        })
+                        .unwrap_or(first_page);
 
-                    Ok(format!("https://{}/i{}", host, url.path()))
+                    Ok((
+                        proxy_path(&mp.image_urls.large)?,
+                        ImageSize::served(original, &mp.image_urls.large),
+                    ))
                 })
-                .collect::<anyhow::Result<Vec<String>>>()?
+                .collect::<anyhow::Result<Vec<_>>>()?
+                .into_iter()
+                .unzip()
         };
 
+        let mosaic_proxy_url = (page_count > 1).then(|| format!("/i/mosaic/{}.jpg", illust_id));
+
+        let blur_proxy_url =
+            (restriction != Restriction::Safe).then(|| format!("/i/blur/{}.jpg", illust_id));
+
         Ok(Self {
+            illust_id,
             image_proxy_urls,
+            image_sizes,
+            mosaic_proxy_url,
+            blur_proxy_url,
             title: ajax_response.body.title,
             ai_generated,
+            restriction,
+            sanity_level,
             description: ajax_response.body.description,
             tags,
             url: ajax_response.body.extra_data.meta.canonical,
             author_name: ajax_response.body.author_name,
             author_id: ajax_response.body.author_id,
+            author_account: app_response.illust.user.account,
+            author_avatar_proxy_url,
+            created_at: ajax_response.body.create_date,
+            uploaded_at: ajax_response.body.upload_date,
+            bookmarks: ajax_response.body.bookmark_count,
+            likes: ajax_response.body.like_count,
+            views: ajax_response.body.view_count,
+            comments: ajax_response.body.comment_count,
+            ugoira,
         })
     }
 
-    pub fn to_template(self, image_index: Option<usize>, host: String) -> ArtworkTemplate {
-        let index = image_index
-            .unwrap_or(1)
-            .min(self.image_proxy_urls.len())
-            .saturating_sub(1);
+    /// Copies a cached listing, making its image urls absolute and signed for `host`.
+    fn with_host(&self, host: &str, signer: &UrlSigner) -> Self {
+        let absolute = |path: &String| signer.signed_url(host, path);
+
+        Self {
+            image_proxy_urls: self.image_proxy_urls.iter().map(absolute).collect(),
+            mosaic_proxy_url: self.mosaic_proxy_url.as_ref().map(absolute),
+            blur_proxy_url: self.blur_proxy_url.as_ref().map(absolute),
+            author_avatar_proxy_url: sign_path(host, &self.author_avatar_proxy_url, signer),
+            ..self.clone()
+        }
+    }
+
+    /// Blurs or drops the images of a restricted work according to `policy`, leaving the blurred
+    /// preview as its only page under the blur policy.
+    pub fn with_policy(self, policy: SensitivePolicy) -> Self {
+        if policy.shows(self.restriction) {
+            return self;
+        }
+
+        let (image_proxy_urls, image_sizes) = match (policy, &self.blur_proxy_url) {
+            (SensitivePolicy::Blur, Some(blur_proxy_url)) => (
+                vec![blur_proxy_url.clone()],
+                vec![sensitive::blur_size(self.image_sizes[0])],
+            ),
+            _ => (Vec::new(), Vec::new()),
+        };
+
+        Self {
+            image_proxy_urls,
+            image_sizes,
+            mosaic_proxy_url: None,
+            ..self
+        }
+    }
+
+    /// Builds the embed, falling back to the mosaic or first page when no index is given.
+    /// Without an index, multi-page works also link their Mastodon status, which clients such as
+    /// Discord render as a gallery of every page.
+    ///
+    /// The title and description follow the configured formats. Restricted works have their
+    /// image blurred or dropped according to the host's sensitive content policy.
+    pub fn to_template(
+        self,
+        image_index: Option<ImageIndex>,
+        config: &Config,
+        signer: &UrlSigner,
+        host: String,
+    ) -> ArtworkTemplate {
+        let policy = config.sensitive_policy(&host);
+
+        let gallery = image_index.is_none() && self.image_proxy_urls.len() > 1;
 
-        let image_proxy_url = self.image_proxy_urls[index].clone();
+        let image_index = image_index.unwrap_or(if config.mosaic_default {
+            ImageIndex::Mosaic
+        } else {
+            ImageIndex::Page(1)
+        });
+
+        let image = match (image_index, &self.mosaic_proxy_url) {
+            (ImageIndex::Mosaic, Some(mosaic_proxy_url)) => (
+                mosaic_proxy_url.clone(),
+                mosaic::mosaic_size(
+                    self.image_sizes[0],
+                    self.image_proxy_urls.len().min(config.mosaic_pages),
+                ),
+            ),
+            (ImageIndex::Mosaic, None) => (self.image_proxy_urls[0].clone(), self.image_sizes[0]),
+            (ImageIndex::Page(index), _) => {
+                let index = index.min(self.image_proxy_urls.len()).saturating_sub(1);
+
+                (
+                    self.image_proxy_urls[index].clone(),
+                    self.image_sizes[index],
+                )
+            }
+        };
 
-        let tag_string = Itertools::intersperse_with(self.tags.into_iter(), || String::from(", "))
-            .collect::<String>();
+        // Pages can be scaled by the proxy, phixiv's own renders cannot
+        let scalable = !self.ugoira
+            && (matches!(image_index, ImageIndex::Page(_)) || self.mosaic_proxy_url.is_none());
 
-        let description = Itertools::intersperse_with(
-            [
-                String::from(if self.ai_generated {
-                    "AI Generated\n"
+        let image = match image {
+            (url, size) if scalable && config.embed_image_width > 0 => {
+                let bounds = ImageSize {
+                    width: config.embed_image_width,
+                    height: u32::MAX,
+                };
+
+                let scaled = transform::scaled_size(size, bounds);
+
+                // Keeping the source format, so `og:image:type` still holds
+                let format = if image_type(&url) == "image/png" {
+                    "png"
                 } else {
-                    ""
-                }),
-                self.description,
-                tag_string.clone(),
-            ]
-            .into_iter()
-            .filter(|s| !s.is_empty()),
-            || String::from("\n"),
-        )
-        .collect::<String>();
+                    "jpg"
+                };
+
+                (
+                    transform::scaled_url(signer, &url, scaled, size, Some(format)),
+                    scaled,
+                )
+            }
+            image => image,
+        };
+
+        // Restricted works only show their own image under the normal policy
+        let shown = policy.shows(self.restriction);
+
+        let image = match policy {
+            _ if shown => Some(image),
+            SensitivePolicy::Blur => self
+                .blur_proxy_url
+                .map(|url| (url, sensitive::blur_size(self.image_sizes[0]))),
+            _ => None,
+        };
+
+        let activity_url = (gallery && shown).then(|| {
+            format!(
+                "https://{host}/users/{}/statuses/{}",
+                self.author_id, self.illust_id
+            )
+        });
+
+        let tag_string = self.tags.join(", ");
+
+        let page = match image_index {
+            ImageIndex::Page(index) => index.clamp(1, self.image_proxy_urls.len()).to_string(),
+            ImageIndex::Mosaic if self.mosaic_proxy_url.is_some() => String::new(),
+            ImageIndex::Mosaic => String::from("1"),
+        };
+
+        let value = |field| match field {
+            Field::Title => self.title.clone(),
+            Field::Author => self.author_name.clone(),
+            Field::Description => self.description.clone(),
+            Field::Page => page.clone(),
+            Field::Pages => self.image_proxy_urls.len().to_string(),
+            Field::Bookmarks => self.bookmarks.to_string(),
+            Field::Views => self.views.to_string(),
+            Field::Likes => self.likes.to_string(),
+            Field::Comments => self.comments.to_string(),
+            Field::Date => self.created_at.chars().take(10).collect(),
+            Field::Updated => self.uploaded_at.chars().take(10).collect(),
+            Field::Stats => format!(
+                "❤ {} · 👁 {} · {}",
+                format::compact(self.bookmarks),
+                format::compact(self.views),
+                self.created_at.chars().take(10).collect::<String>()
+            ),
+            Field::Tags => tag_string.clone(),
+            Field::Ai => String::from(if self.ai_generated {
+                "AI Generated"
+            } else {
+                ""
+            }),
+            Field::Restriction => self.restriction.label().unwrap_or_default().to_string(),
+        };
+
+        let title = format::truncate(
+            config.embed_title_format.render(value),
+            config.embed_title_max_length,
+        );
+
+        let description = format::truncate(
+            config.embed_description_format.render(value),
+            config.embed_description_max_length,
+        );
+
+        let video_url = image
+            .as_ref()
+            .filter(|_| self.ugoira && shown)
+            .map(|(url, _)| url.clone());
 
         ArtworkTemplate {
-            image_proxy_url,
-            title: self.title,
+            video_url,
+            image_type: image.as_ref().map(|(url, _)| image_type(url)),
+            image_size: image.as_ref().map(|(_, size)| *size),
+            image_proxy_url: image.map(|(url, _)| url),
+            title,
             description,
             author_name: self.author_name,
             author_id: self.author_id,
             url: self.url,
             alt_text: tag_string,
             host,
+            activity_url,
         }
     }
 }
@@ -12,6 +12,22 @@ pub(super) struct IllustrationResponse {
     pub image_urls: ImageUrls,
     pub meta_pages: Vec<MetaPage>,
     pub illust_ai_type: u8,
+    #[serde(rename = "type")]
+    pub illust_type: String,
+    /// False when pixiv hides the work from this account, e.g. R-18 works with the filter enabled
+    pub visible: Option<bool>,
+    pub x_restrict: u8,
+    pub sanity_level: u8,
+    /// Size of the first page
+    pub width: u32,
+    pub height: u32,
+    pub user: IllustUser,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct IllustUser {
+    pub account: String,
+    pub profile_image_urls: ProfileImageUrls,
 }
 
 #[derive(Debug, Deserialize)]
@@ -43,10 +59,33 @@ pub(super) struct AjaxBody {
     pub author_id: String,
     #[serde(rename = "userName")]
     pub author_name: String,
+    #[serde(rename = "bookmarkCount")]
+    pub bookmark_count: u64,
+    #[serde(rename = "likeCount")]
+    pub like_count: u64,
+    #[serde(rename = "viewCount")]
+    pub view_count: u64,
+    #[serde(rename = "commentCount")]
+    pub comment_count: u64,
+    #[serde(rename = "createDate")]
+    pub create_date: String,
+    #[serde(rename = "uploadDate")]
+    pub upload_date: String,
     #[serde(rename = "extraData")]
     pub extra_data: AjaxExtraData,
 }
 
+#[derive(Debug, Default, Deserialize)]
+pub(super) struct AjaxPagesResponse {
+    pub body: Vec<AjaxPage>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct AjaxPage {
+    pub width: u32,
+    pub height: u32,
+}
+
 #[derive(Debug, Deserialize)]
 pub(super) struct Tags {
     pub tags: Vec<Tag>,
@@ -67,3 +106,116 @@ pub(super) struct AjaxExtraData {
 pub(super) struct AjaxMeta {
     pub canonical: String,
 }
+
+#[derive(Debug, Deserialize)]
+pub(super) struct UgoiraMetadataResponse {
+    pub ugoira_metadata: UgoiraMetadataBody,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct UgoiraMetadataBody {
+    pub zip_urls: UgoiraZipUrls,
+    pub frames: Vec<UgoiraFrame>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct UgoiraZipUrls {
+    pub medium: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UgoiraFrame {
+    pub file: String,
+    /// Frame delay in milliseconds
+    pub delay: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct AppNovelResponse {
+    pub novel: NovelResponse,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct NovelResponse {
+    pub image_urls: ImageUrls,
+    pub novel_ai_type: u8,
+    pub x_restrict: u8,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct AjaxNovelResponse {
+    pub body: AjaxNovelBody,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct AjaxNovelBody {
+    pub title: String,
+    pub description: String,
+    pub content: String,
+    pub tags: Tags,
+    #[serde(rename = "userId")]
+    pub author_id: String,
+    #[serde(rename = "userName")]
+    pub author_name: String,
+    #[serde(rename = "wordCount")]
+    pub word_count: u64,
+    #[serde(rename = "characterCount")]
+    pub character_count: u64,
+    #[serde(rename = "seriesNavData")]
+    pub series_nav_data: Option<AjaxSeriesNavData>,
+    #[serde(rename = "extraData")]
+    pub extra_data: AjaxExtraData,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct AjaxSeriesNavData {
+    #[serde(rename = "seriesId")]
+    pub series_id: u64,
+    pub title: String,
+    pub order: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct AppUserResponse {
+    pub user: UserResponse,
+    pub profile: UserProfile,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct UserResponse {
+    pub name: String,
+    pub account: String,
+    pub profile_image_urls: ProfileImageUrls,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct ProfileImageUrls {
+    pub medium: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct UserProfile {
+    pub total_follow_users: u64,
+    pub total_illusts: u64,
+    pub total_manga: u64,
+    pub total_novels: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct AppUserIllustsResponse {
+    pub illusts: Vec<UserIllust>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct UserIllust {
+    pub id: u64,
+    pub title: String,
+    pub x_restrict: u8,
+    pub image_urls: UserIllustImageUrls,
+}
+
+#[derive(Debug, Deserialize)]
+pub(super) struct UserIllustImageUrls {
+    pub square_medium: String,
+}
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+
+use askama::Template;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{sensitive::SensitivePolicy, signature::UrlSigner, upstream::Upstream};
+
+use super::{
+    app_headers, check_status, hashtags,
+    model::{AjaxNovelResponse, AppNovelResponse},
+    proxy_url, Restriction,
+};
+
+const NOVEL_URL: &str = "https://app-api.pixiv.net/v2/novel/detail";
+
+/// Number of characters of the novel body shown in the embed
+const EXCERPT_LENGTH: usize = 200;
+
+#[derive(Deserialize)]
+pub struct RawNovelPath {
+    pub language: Option<String>,
+    pub id: String,
+}
+
+#[derive(Debug, Serialize, Template)]
+#[template(path = "novel.html")]
+pub struct NovelTemplate {
+    pub cover_proxy_url: Option<String>,
+    pub title: String,
+    pub description: String,
+    pub author_name: String,
+    pub author_id: String,
+    pub url: String,
+    pub host: String,
+}
+
+#[derive(Serialize)]
+pub struct NovelSeries {
+    pub id: u64,
+    pub title: String,
+    pub order: u32,
+}
+
+#[derive(Serialize)]
+/// Representing a novel, uniquely determined by language and novel_id
+pub struct NovelListing {
+    pub cover_proxy_url: String,
+    pub title: String,
+    pub ai_generated: bool,
+    pub restriction: Restriction,
+    pub description: String,
+    pub excerpt: String,
+    pub word_count: u64,
+    pub character_count: u64,
+    pub series: Option<NovelSeries>,
+    pub tags: Vec<String>,
+    pub url: String,
+    pub author_name: String,
+    pub author_id: String,
+}
+
+async fn app_novel_request(
+    novel_id: &String,
+    access_token: &str,
+    upstream: &Upstream,
+) -> anyhow::Result<AppNovelResponse> {
+    let app_params = HashMap::from([("novel_id", novel_id)]);
+
+    let response = upstream
+        .send(
+            "novel",
+            upstream
+                .client()
+                .get(NOVEL_URL)
+                .headers(app_headers(access_token)?)
+                .query(&app_params),
+        )
+        .await?;
+
+    Ok(check_status(response).await?.json().await?)
+}
+
+async fn ajax_novel_request(
+    novel_id: &String,
+    language: &Option<String>,
+    upstream: &Upstream,
+) -> anyhow::Result<AjaxNovelResponse> {
+    let response = upstream
+        .send(
+            "novel_ajax",
+            upstream.client().get(format!(
+                "https://www.pixiv.net/ajax/novel/{}?lang={}",
+                &novel_id,
+                &language.clone().unwrap_or_else(|| String::from("jp"))
+            )),
+        )
+        .await?;
+
+    Ok(check_status(response).await?.json().await?)
+}
+
+/// Takes the opening of a novel body, dropping pixiv's page break markup.
+fn excerpt(content: &str) -> String {
+    let text = content
+        .replace("[newpage]", "\n")
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .join("\n");
+
+    match text.char_indices().nth(EXCERPT_LENGTH) {
+        Some((end, _)) => format!("{}…", &text[..end]),
+        None => text,
+    }
+}
+
+impl NovelListing {
+    pub async fn get_listing(
+        language: Option<String>,
+        novel_id: String,
+        access_token: String,
+        host: &str,
+        upstream: &Upstream,
+        signer: &UrlSigner,
+    ) -> anyhow::Result<Self> {
+        let (app_response, ajax_response) = tokio::try_join!(
+            app_novel_request(&novel_id, &access_token, upstream),
+            ajax_novel_request(&novel_id, &language, upstream),
+        )?;
+
+        Ok(Self {
+            cover_proxy_url: proxy_url(host, &app_response.novel.image_urls.large, signer)?,
+            title: ajax_response.body.title,
+            ai_generated: app_response.novel.novel_ai_type == 2,
+            restriction: Restriction::from_x_restrict(app_response.novel.x_restrict),
+            description: ajax_response.body.description,
+            excerpt: excerpt(&ajax_response.body.content),
+            word_count: ajax_response.body.word_count,
+            character_count: ajax_response.body.character_count,
+            series: ajax_response
+                .body
+                .series_nav_data
+                .map(|series| NovelSeries {
+                    id: series.series_id,
+                    title: series.title,
+                    order: series.order,
+                }),
+            tags: hashtags(ajax_response.body.tags, &language),
+            url: ajax_response.body.extra_data.meta.canonical,
+            author_name: ajax_response.body.author_name,
+            author_id: ajax_response.body.author_id,
+        })
+    }
+
+    /// Builds the embed. Restricted novels only show their cover and excerpt when `policy`
+    /// embeds them normally, covers are not blurred.
+    pub fn to_template(self, policy: SensitivePolicy, host: String) -> NovelTemplate {
+        let shown = policy.shows(self.restriction);
+
+        let title = match self.restriction.label() {
+            Some(label) => format!("[{label}] {}", self.title),
+            None => self.title,
+        };
+
+        let tag_string = Itertools::intersperse_with(self.tags.into_iter(), || String::from(", "))
+            .collect::<String>();
+
+        let description = Itertools::intersperse_with(
+            [
+                String::from(if self.ai_generated {
+                    "AI Generated\n"
+                } else {
+                    ""
+                }),
+                self.series
+                    .map(|series| format!("{} #{}", series.title, series.order))
+                    .unwrap_or_default(),
+                format!(
+                    "{} words, {} characters",
+                    self.word_count, self.character_count
+                ),
+                tag_string,
+                if shown { self.excerpt } else { String::new() },
+            ]
+            .into_iter()
+            .filter(|s| !s.is_empty()),
+            || String::from("\n"),
+        )
+        .collect::<String>();
+
+        NovelTemplate {
+            cover_proxy_url: shown.then_some(self.cover_proxy_url),
+            title,
+            description,
+            author_name: self.author_name,
+            author_id: self.author_id,
+            url: self.url,
+            host,
+        }
+    }
+}
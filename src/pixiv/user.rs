@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+
+use askama::Template;
+use serde::{Deserialize, Serialize};
+
+use crate::{sensitive::SensitivePolicy, signature::UrlSigner, upstream::Upstream};
+
+use super::{
+    app_headers, check_status,
+    model::{AppUserIllustsResponse, AppUserResponse},
+    proxy_url, Restriction,
+};
+
+const USER_URL: &str = "https://app-api.pixiv.net/v1/user/detail";
+const USER_ILLUSTS_URL: &str = "https://app-api.pixiv.net/v1/user/illusts";
+
+/// Number of recent works shown alongside a profile
+const LATEST_WORKS: usize = 4;
+
+/// pixiv's own account, requested to check the app API is reachable and accepts our token
+const PROBE_USER_ID: &str = "11";
+
+#[derive(Deserialize)]
+pub struct RawUserPath {
+    pub language: Option<String>,
+    pub id: String,
+}
+
+#[derive(Debug, Serialize, Template)]
+#[template(path = "user.html")]
+pub struct UserTemplate {
+    pub avatar_proxy_url: String,
+    pub work_proxy_urls: Vec<String>,
+    pub title: String,
+    pub description: String,
+    pub name: String,
+    pub user_id: String,
+    pub url: String,
+    pub host: String,
+}
+
+#[derive(Serialize)]
+pub struct UserWork {
+    pub id: u64,
+    pub title: String,
+    pub restriction: Restriction,
+    pub image_proxy_url: String,
+    pub url: String,
+}
+
+#[derive(Serialize)]
+/// Representing a pixiv user profile, uniquely determined by user_id
+pub struct UserListing {
+    pub avatar_proxy_url: String,
+    pub name: String,
+    pub account: String,
+    pub user_id: String,
+    pub bio: String,
+    pub following: u64,
+    pub total_illusts: u64,
+    pub total_manga: u64,
+    pub total_novels: u64,
+    pub latest_works: Vec<UserWork>,
+    pub url: String,
+}
+
+async fn app_user_request(
+    user_id: &String,
+    access_token: &str,
+    upstream: &Upstream,
+) -> anyhow::Result<AppUserResponse> {
+    let app_params = HashMap::from([("user_id", user_id)]);
+
+    let response = upstream
+        .send(
+            "user",
+            upstream
+                .client()
+                .get(USER_URL)
+                .headers(app_headers(access_token)?)
+                .query(&app_params),
+        )
+        .await?;
+
+    Ok(check_status(response).await?.json().await?)
+}
+
+async fn app_user_illusts_request(
+    user_id: &str,
+    access_token: &str,
+    upstream: &Upstream,
+) -> anyhow::Result<AppUserIllustsResponse> {
+    let app_params = HashMap::from([("user_id", user_id), ("type", "illust")]);
+
+    let response = upstream
+        .send(
+            "user_illusts",
+            upstream
+                .client()
+                .get(USER_ILLUSTS_URL)
+                .headers(app_headers(access_token)?)
+                .query(&app_params),
+        )
+        .await?;
+
+    Ok(check_status(response).await?.json().await?)
+}
+
+/// Makes a cheap app API request, failing if pixiv is unreachable or rejects `access_token`.
+pub async fn probe(access_token: String, upstream: &Upstream) -> anyhow::Result<()> {
+    app_user_request(&PROBE_USER_ID.to_string(), &access_token, upstream).await?;
+
+    Ok(())
+}
+
+impl UserListing {
+    pub async fn get_listing(
+        user_id: String,
+        access_token: String,
+        host: &str,
+        upstream: &Upstream,
+        signer: &UrlSigner,
+    ) -> anyhow::Result<Self> {
+        let (user_response, illusts_response) = tokio::try_join!(
+            app_user_request(&user_id, &access_token, upstream),
+            app_user_illusts_request(&user_id, &access_token, upstream),
+        )?;
+
+        let latest_works = illusts_response
+            .illusts
+            .into_iter()
+            .take(LATEST_WORKS)
+            .map(|illust| {
+                Ok(UserWork {
+                    image_proxy_url: proxy_url(host, &illust.image_urls.square_medium, signer)?,
+                    url: format!("https://www.pixiv.net/artworks/{}", illust.id),
+                    id: illust.id,
+                    title: illust.title,
+                    restriction: Restriction::from_x_restrict(illust.x_restrict),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let profile = user_response.profile;
+
+        Ok(Self {
+            avatar_proxy_url: proxy_url(
+                host,
+                &user_response.user.profile_image_urls.medium,
+                signer,
+            )?,
+            name: user_response.user.name,
+            account: user_response.user.account,
+            url: format!("https://www.pixiv.net/users/{user_id}"),
+            user_id,
+            bio: user_response.user.comment.unwrap_or_default(),
+            following: profile.total_follow_users,
+            total_illusts: profile.total_illusts,
+            total_manga: profile.total_manga,
+            total_novels: profile.total_novels,
+            latest_works,
+        })
+    }
+
+    /// Blurs the latest works that `policy` does not show, or leaves them out unless it blurs
+    /// them, with blurred previews signed for `host`.
+    pub fn with_policy(self, policy: SensitivePolicy, host: &str, signer: &UrlSigner) -> Self {
+        let latest_works = self
+            .latest_works
+            .into_iter()
+            .filter_map(|work| match policy {
+                _ if policy.shows(work.restriction) => Some(work),
+                SensitivePolicy::Blur => Some(UserWork {
+                    image_proxy_url: signer.signed_url(host, &format!("/i/blur/{}.jpg", work.id)),
+                    ..work
+                }),
+                _ => None,
+            })
+            .collect();
+
+        Self {
+            latest_works,
+            ..self
+        }
+    }
+
+    /// Builds the embed, leaving out restricted works unless `policy` embeds them normally.
+    pub fn to_template(self, policy: SensitivePolicy, host: String) -> UserTemplate {
+        let counts = format!(
+            "{} following · {} illustrations · {} manga · {} novels",
+            self.following, self.total_illusts, self.total_manga, self.total_novels
+        );
+
+        let description = [counts, self.bio]
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        UserTemplate {
+            avatar_proxy_url: self.avatar_proxy_url,
+            work_proxy_urls: self
+                .latest_works
+                .into_iter()
+                .filter(|work| policy.shows(work.restriction))
+                .map(|work| work.image_proxy_url)
+                .collect(),
+            title: format!("{} (@{})", self.name, self.account),
+            description,
+            name: self.name,
+            user_id: self.user_id,
+            url: self.url,
+            host,
+        }
+    }
+}
@@ -1,50 +1,469 @@
-use std::{sync::Arc, time::Duration};
+use std::{io::SeekFrom, ops::Bound, sync::Arc, time::Duration};
 
 use axum::{
-    body::StreamBody,
-    extract::{Path, State},
-    headers::CacheControl,
+    body::{Bytes, StreamBody},
+    extract::{Path, Query, State},
+    headers::{
+        CacheControl, ContentLength, ContentRange, ETag, HeaderMapExt, IfModifiedSince,
+        IfNoneMatch, IfRange, LastModified, Range,
+    },
     middleware,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
     routing::get,
-    Router, TypedHeader,
+    Router,
 };
-use tokio::sync::RwLock;
+use futures::TryStreamExt;
+use http::{
+    header::{
+        ACCEPT, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG,
+        IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE, VARY,
+    },
+    HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
 
 use crate::{
+    disk_cache::CacheEntry,
+    download::{Download, Head},
     helper::{self, PhixivError},
-    state::{authorized_middleware, PhixivState},
+    metrics,
+    mosaic::mosaic_handler,
+    pixiv::{check_account, check_status, PixivError},
+    sensitive::blur_handler,
+    signature::signature_middleware,
+    state::PhixivState,
+    transform::{Transform, TransformParams},
+    ugoira::ugoira_handler,
+    upstream::Upstream,
 };
 
+/// Upstream response headers passed through to clients
+const PASSTHROUGH_HEADERS: [HeaderName; 6] = [
+    CONTENT_TYPE,
+    CONTENT_LENGTH,
+    LAST_MODIFIED,
+    ETAG,
+    ACCEPT_RANGES,
+    CONTENT_RANGE,
+];
+
+/// Upstream response headers stored with cached images, the rest are derived when serving
+const CACHED_HEADERS: [HeaderName; 3] = [CONTENT_TYPE, LAST_MODIFIED, ETAG];
+
+/// Request headers forwarded upstream, requests carrying any of them bypass the shared download
+const FORWARDED_HEADERS: [HeaderName; 4] = [RANGE, IF_RANGE, IF_NONE_MATCH, IF_MODIFIED_SINCE];
+
+/// Response head stored alongside a cached image
+#[derive(Serialize, Deserialize)]
+struct CachedHead {
+    headers: Vec<(String, String)>,
+}
+
+fn cached_metadata(head: &Head) -> Vec<u8> {
+    let cached = CachedHead {
+        headers: CACHED_HEADERS
+            .iter()
+            .filter_map(|name| {
+                let value = head.headers.get(name)?.to_str().ok()?;
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect(),
+    };
+
+    serde_json::to_vec(&cached).unwrap_or_default()
+}
+
+/// Builds a response with the upstream status and passthrough headers.
+fn proxied_response(status: StatusCode, upstream: &HeaderMap, body: impl IntoResponse) -> Response {
+    let mut response = body.into_response();
+
+    *response.status_mut() = status;
+
+    for name in PASSTHROUGH_HEADERS {
+        if let Some(value) = upstream.get(&name) {
+            response.headers_mut().insert(name, value.clone());
+        }
+    }
+
+    let cache_control = if status.is_success() || status == StatusCode::NOT_MODIFIED {
+        CacheControl::new()
+            .with_max_age(Duration::from_secs(60 * 60 * 24))
+            .with_public()
+    } else {
+        CacheControl::new().with_no_store()
+    };
+
+    response.headers_mut().typed_insert(cache_control);
+
+    response
+}
+
+/// Streams an upstream response straight through to the client.
+fn streamed_response(response: reqwest::Response) -> Response {
+    let status = response.status();
+    let headers = response.headers().clone();
+
+    proxied_response(status, &headers, StreamBody::new(response.bytes_stream()))
+}
+
+/// Resolves a single byte range against a body of `len` bytes, as inclusive bounds.
+fn satisfiable_range(range: &Range, len: u64) -> Option<(u64, u64)> {
+    let mut ranges = range.iter();
+    let bounds = ranges.next()?;
+
+    if ranges.next().is_some() {
+        return None;
+    }
+
+    let (start, end) = match bounds {
+        (Bound::Included(start), Bound::Included(end)) => (start, end.min(len.checked_sub(1)?)),
+        (Bound::Included(start), Bound::Unbounded) => (start, len.checked_sub(1)?),
+        (Bound::Unbounded, Bound::Included(suffix)) if suffix > 0 => {
+            (len.saturating_sub(suffix), len.checked_sub(1)?)
+        }
+        _ => return None,
+    };
+
+    (start <= end).then_some((start, end))
+}
+
+/// Serves an image from the disk cache, answering conditional and range requests locally.
+async fn cached_response(
+    mut entry: CacheEntry,
+    method: &Method,
+    request_headers: &HeaderMap,
+) -> Result<Response, PhixivError> {
+    let cached: CachedHead = serde_json::from_slice(&entry.metadata)?;
+
+    let mut headers = HeaderMap::new();
+
+    for (name, value) in cached.headers {
+        headers.insert(HeaderName::try_from(name)?, HeaderValue::try_from(value)?);
+    }
+
+    headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    let etag = headers.typed_get::<ETag>();
+    let last_modified = headers.typed_get::<LastModified>();
+
+    let not_modified = match request_headers.typed_get::<IfNoneMatch>() {
+        Some(if_none_match) => etag
+            .as_ref()
+            .is_some_and(|etag| !if_none_match.precondition_passes(etag)),
+        None => match (
+            request_headers.typed_get::<IfModifiedSince>(),
+            last_modified,
+        ) {
+            (Some(if_modified_since), Some(last_modified)) => {
+                !if_modified_since.is_modified(last_modified.into())
+            }
+            _ => false,
+        },
+    };
+
+    if not_modified {
+        return Ok(proxied_response(StatusCode::NOT_MODIFIED, &headers, ()));
+    }
+
+    // A stale If-Range means the client's partial copy is outdated, so send everything
+    let range = request_headers.typed_get::<Range>().filter(|_| {
+        request_headers
+            .typed_get::<IfRange>()
+            .is_none_or(|if_range| !if_range.is_modified(etag.as_ref(), last_modified.as_ref()))
+    });
+
+    let (status, start, length) = match range {
+        Some(range) => match satisfiable_range(&range, entry.body_len) {
+            Some((start, end)) => {
+                headers.typed_insert(ContentRange::bytes(start..=end, entry.body_len)?);
+                (StatusCode::PARTIAL_CONTENT, start, end - start + 1)
+            }
+            None => {
+                headers.typed_insert(ContentRange::unsatisfied_bytes(entry.body_len));
+                return Ok(proxied_response(
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    &headers,
+                    (),
+                ));
+            }
+        },
+        None => (StatusCode::OK, 0, entry.body_len),
+    };
+
+    headers.typed_insert(ContentLength(length));
+
+    if method == Method::HEAD {
+        return Ok(proxied_response(status, &headers, ()));
+    }
+
+    entry
+        .file
+        .seek(SeekFrom::Start(entry.body_offset + start))
+        .await?;
+
+    Ok(proxied_response(
+        status,
+        &headers,
+        StreamBody::new(ReaderStream::new(entry.file.take(length))),
+    ))
+}
+
+/// Requests `path` from `i.pximg.net` with `access_token` and the `forwarded` request headers.
+async fn pximg_request(
+    upstream: &Upstream,
+    method: &Method,
+    path: &str,
+    access_token: String,
+    forwarded: &HeaderMap,
+) -> anyhow::Result<reqwest::Response> {
+    let mut headers = helper::pximg_headers(&access_token)?;
+    headers.extend(forwarded.clone());
+
+    let request = upstream
+        .client()
+        .request(method.clone(), format!("https://i.pximg.net/{path}"))
+        .headers(headers);
+
+    Ok(check_account(upstream.send_relayed("pximg", request).await?).await?)
+}
+
+/// Requests `path` for this request alone, on a leased account.
+async fn direct_request(
+    state: &PhixivState,
+    method: &Method,
+    path: &str,
+    forwarded: &HeaderMap,
+) -> anyhow::Result<reqwest::Response> {
+    state
+        .accounts
+        .lease()?
+        .track(|access_token| pximg_request(&state.upstream, method, path, access_token, forwarded))
+        .await
+}
+
+/// Joins the shared download of `path`, which fills the disk cache as it goes.
+fn shared_download(state: &PhixivState, path: &str) -> anyhow::Result<Arc<Download>> {
+    let account = state.accounts.lease()?;
+    let upstream = state.upstream.clone();
+    let owned_path = path.to_string();
+    let forwarded = HeaderMap::new();
+
+    Ok(state.downloads.join(
+        path,
+        async move {
+            account
+                .track(|access_token| {
+                    pximg_request(
+                        &upstream,
+                        &Method::GET,
+                        &owned_path,
+                        access_token,
+                        &forwarded,
+                    )
+                })
+                .await
+        },
+        state.images.clone(),
+        cached_metadata,
+    ))
+}
+
+/// Reads the whole original image, from the disk cache when possible.
+async fn source_image(state: &PhixivState, path: &str) -> anyhow::Result<Bytes> {
+    if let Some(images) = &state.images {
+        let entry = images.get(path).await;
+
+        metrics::METRICS.cache_lookup("images", entry.is_some());
+
+        if let Some(entry) = entry {
+            return read_entry(entry).await;
+        }
+    }
+
+    if let Some((head, body)) = shared_download(state, path)?.follow().await? {
+        if head.status != StatusCode::OK {
+            return Err(PixivError::from_status(head.status).into());
+        }
+
+        let chunks: Vec<Bytes> = body.try_collect().await?;
+
+        return Ok(chunks.concat().into());
+    }
+
+    // The download finished before it could be followed
+    if let Some(entry) = cached_entry(state, path).await {
+        return read_entry(entry).await;
+    }
+
+    let response = direct_request(state, &Method::GET, path, &HeaderMap::new()).await?;
+
+    Ok(check_status(response).await?.bytes().await?)
+}
+
+async fn cached_entry(state: &PhixivState, path: &str) -> Option<CacheEntry> {
+    state.images.as_ref()?.get(path).await
+}
+
+async fn read_entry(mut entry: CacheEntry) -> anyhow::Result<Bytes> {
+    let mut body = Vec::with_capacity(entry.body_len as usize);
+    entry.file.read_to_end(&mut body).await?;
+
+    Ok(body.into())
+}
+
+/// Serves a resized or re-encoded variant of the image at `path`, rendering it on first request.
+async fn transformed_response(
+    state: &PhixivState,
+    path: &str,
+    transform: Transform,
+) -> Result<Response, PhixivError> {
+    let image = state
+        .render(transform.cache_key(path), async {
+            let source = source_image(state, path).await?;
+            let image = tokio::task::spawn_blocking(move || transform.apply(&source)).await??;
+
+            Ok::<_, anyhow::Error>(Bytes::from(image))
+        })
+        .await?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(transform.format.content_type()),
+    );
+
+    let mut response = proxied_response(StatusCode::OK, &headers, image);
+
+    if transform.negotiated {
+        response
+            .headers_mut()
+            .insert(VARY, HeaderValue::from_static("accept"));
+    }
+
+    Ok(response)
+}
+
 async fn proxy_handler(
-    State(state): State<Arc<RwLock<PhixivState>>>,
+    State(state): State<Arc<PhixivState>>,
     Path(path): Path<String>,
-) -> Result<impl IntoResponse, PhixivError> {
-    let state = state.read().await;
+    Query(params): Query<TransformParams>,
+    method: Method,
+    request_headers: HeaderMap,
+) -> Result<Response, PhixivError> {
+    let accept = request_headers
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok());
 
-    let url = format!("https://i.pximg.net/{path}");
+    match Transform::from_params(&params, accept, &path) {
+        Ok(Some(transform)) => return transformed_response(&state, &path, transform).await,
+        Ok(None) => {}
+        Err(error) => return Err(error.into()),
+    }
 
-    let mut headers = helper::headers();
-    headers.append("Referer", "https://www.pixiv.net/".parse()?);
-    headers.append(
-        "Authorization",
-        format!("Bearer {}", state.auth.access_token).parse()?,
-    );
+    if let Some(images) = &state.images {
+        let entry = images.get(&path).await;
+
+        metrics::METRICS.cache_lookup("images", entry.is_some());
+
+        if let Some(entry) = entry {
+            tracing::debug!(path, "image cache hit");
+
+            return cached_response(entry, &method, &request_headers).await;
+        }
+    }
+
+    if method == Method::HEAD {
+        let response = direct_request(&state, &method, &path, &HeaderMap::new()).await?;
 
-    let response = state.client.get(&url).headers(headers).send().await?;
+        return Ok(proxied_response(response.status(), response.headers(), ()));
+    }
 
-    Ok((
-        TypedHeader(
-            CacheControl::new()
-                .with_max_age(Duration::from_secs(60 * 60 * 24))
-                .with_public(),
-        ),
-        StreamBody::new(response.bytes_stream()),
+    let mut forwarded = HeaderMap::new();
+
+    for name in FORWARDED_HEADERS {
+        if let Some(value) = request_headers.get(&name) {
+            forwarded.insert(name, value.clone());
+        }
+    }
+
+    if !forwarded.is_empty() {
+        return Ok(streamed_response(
+            direct_request(&state, &method, &path, &forwarded).await?,
+        ));
+    }
+
+    if let Some((head, body)) = shared_download(&state, &path)?.follow().await? {
+        return Ok(proxied_response(
+            head.status,
+            &head.headers,
+            StreamBody::new(body),
+        ));
+    }
+
+    // The download finished before it could be followed
+    if let Some(entry) = cached_entry(&state, &path).await {
+        return cached_response(entry, &method, &request_headers).await;
+    }
+
+    Ok(streamed_response(
+        direct_request(&state, &method, &path, &forwarded).await?,
     ))
 }
 
-pub fn proxy_router(state: Arc<RwLock<PhixivState>>) -> Router<Arc<RwLock<PhixivState>>> {
+pub fn proxy_router(state: Arc<PhixivState>) -> Router<Arc<PhixivState>> {
     Router::new()
+        .route("/blur/:file", get(blur_handler))
+        .route("/mosaic/:file", get(mosaic_handler))
+        .route("/ugoira/:file", get(ugoira_handler))
         .route("/*path", get(proxy_handler))
-        .layer(middleware::from_fn_with_state(state, authorized_middleware))
+        .layer(middleware::from_fn_with_state(state, signature_middleware))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(value: &str) -> Range {
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, HeaderValue::from_str(value).unwrap());
+
+        headers.typed_get().unwrap()
+    }
+
+    #[test]
+    fn resolves_satisfiable_ranges() {
+        assert_eq!(satisfiable_range(&range("bytes=0-99"), 1000), Some((0, 99)));
+        assert_eq!(
+            satisfiable_range(&range("bytes=900-2000"), 1000),
+            Some((900, 999))
+        );
+        assert_eq!(
+            satisfiable_range(&range("bytes=500-"), 1000),
+            Some((500, 999))
+        );
+        assert_eq!(
+            satisfiable_range(&range("bytes=-100"), 1000),
+            Some((900, 999))
+        );
+        assert_eq!(
+            satisfiable_range(&range("bytes=-2000"), 1000),
+            Some((0, 999))
+        );
+        assert_eq!(
+            satisfiable_range(&range("bytes=999-999"), 1000),
+            Some((999, 999))
+        );
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(satisfiable_range(&range("bytes=1000-"), 1000), None);
+        assert_eq!(satisfiable_range(&range("bytes=1000-1999"), 1000), None);
+        assert_eq!(satisfiable_range(&range("bytes=-0"), 1000), None);
+        assert_eq!(satisfiable_range(&range("bytes=0-"), 0), None);
+        assert_eq!(satisfiable_range(&range("bytes=-100"), 0), None);
+        assert_eq!(satisfiable_range(&range("bytes=0-9,20-29"), 1000), None);
+    }
 }
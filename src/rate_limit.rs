@@ -0,0 +1,403 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use axum::{
+    extract::{ConnectInfo, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{header, HeaderMap, Request};
+use moka::future::Cache;
+
+use crate::{api::ApiError, config::Config, helper::PhixivError, state::PhixivState};
+
+/// Clients idle for this long forget their bucket, which would be full again by then anyway
+const BUCKET_IDLE: Duration = Duration::from_secs(10 * 60);
+
+const MAX_BUCKETS: u64 = 100_000;
+
+/// A token bucket budget, written `<requests per minute>` or `<requests per minute>/<burst>`.
+/// The burst defaults to a full minute of requests, and a rate of 0 disables limiting.
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    per_minute: u32,
+    burst: u32,
+}
+
+impl FromStr for Quota {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (per_minute, burst) = match s.split_once('/') {
+            Some((per_minute, burst)) => (per_minute.trim().parse()?, burst.trim().parse()?),
+            None => {
+                let per_minute = s.trim().parse()?;
+                (per_minute, per_minute)
+            }
+        };
+
+        if per_minute > 0 && burst == 0 {
+            anyhow::bail!("burst must be at least 1");
+        }
+
+        Ok(Self { per_minute, burst })
+    }
+}
+
+/// An IP address or CIDR range, e.g. `10.0.0.0/8`.
+#[derive(Clone, Copy, Debug)]
+pub struct IpRange {
+    address: IpAddr,
+    prefix: u32,
+}
+
+impl FromStr for IpRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.trim().split_once('/') {
+            Some((address, prefix)) => (address.parse::<IpAddr>()?, Some(prefix.parse()?)),
+            None => (s.trim().parse()?, None),
+        };
+
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+
+        if prefix > bits {
+            anyhow::bail!("prefix /{prefix} is too long for {address}");
+        }
+
+        Ok(Self { address, prefix })
+    }
+}
+
+impl IpRange {
+    fn contains(&self, address: IpAddr) -> bool {
+        let (network, address, bits) = match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                (u32::from(network) as u128, u32::from(address) as u128, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                (u128::from(network), u128::from(address), 128)
+            }
+            _ => return false,
+        };
+
+        let host_bits = bits - self.prefix;
+
+        network.checked_shr(host_bits).unwrap_or(0) == address.checked_shr(host_bits).unwrap_or(0)
+    }
+}
+
+/// Comma separated [`IpRange`]s of reverse proxies whose `X-Forwarded-For` is believed.
+#[derive(Clone, Debug, Default)]
+pub struct TrustedProxies(Vec<IpRange>);
+
+impl FromStr for TrustedProxies {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|range| !range.trim().is_empty())
+            .map(|range| {
+                range
+                    .parse()
+                    .with_context(|| format!("invalid range {range}"))
+            })
+            .collect::<anyhow::Result<_>>()
+            .map(Self)
+    }
+}
+
+impl TrustedProxies {
+    fn contains(&self, address: IpAddr) -> bool {
+        self.0.iter().any(|range| range.contains(address))
+    }
+
+    /// The client behind any trusted proxies: the last address in `X-Forwarded-For` not
+    /// added by one of them, or the peer itself when it is not a trusted proxy.
+    fn client(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.contains(peer) {
+            return peer;
+        }
+
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|address| address.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<_>>();
+
+        forwarded
+            .iter()
+            .rev()
+            .find(|address| !self.contains(**address))
+            .or(forwarded.first())
+            .copied()
+            .unwrap_or(peer)
+    }
+}
+
+/// Returned when a client has used up its budget.
+#[derive(Debug)]
+pub struct Throttled(pub Duration);
+
+impl fmt::Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Too many requests, try again in {} seconds",
+            self.0.as_secs().max(1)
+        )
+    }
+}
+
+impl std::error::Error for Throttled {}
+
+/// Clients with separate budgets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Class {
+    /// Embed crawlers such as Discord's, fetching on behalf of many users
+    Crawler,
+    /// People following links or viewing images directly
+    Human,
+    /// Callers of `/api`
+    Api,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Takes a token, or returns how long until one is available.
+    fn take(&mut self, quota: Quota) -> Result<(), Duration> {
+        let now = Instant::now();
+        let rate = quota.per_minute as f64 / 60.0;
+
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * rate)
+            .min(quota.burst as f64);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+}
+
+/// Token buckets per client IP and [`Class`].
+pub struct RateLimiter {
+    crawlers: Quota,
+    humans: Quota,
+    api: Quota,
+    trusted_proxies: TrustedProxies,
+    bots: isbot::Bots,
+    buckets: Cache<(Class, IpAddr), Arc<Mutex<Bucket>>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            crawlers: config.rate_limit_crawlers,
+            humans: config.rate_limit_humans,
+            api: config.rate_limit_api,
+            trusted_proxies: config.trusted_proxies.clone(),
+            bots: isbot::Bots::default(),
+            buckets: Cache::builder()
+                .max_capacity(MAX_BUCKETS)
+                .time_to_idle(BUCKET_IDLE)
+                .build(),
+        }
+    }
+
+    fn class(&self, path: &str, headers: &HeaderMap) -> Class {
+        // Mastodon statuses are fetched by crawlers unfurling embeds, not by API users
+        if path.starts_with("/api/") && !path.starts_with("/api/v1/statuses/") {
+            return Class::Api;
+        }
+
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|user_agent| user_agent.to_str().ok())
+            .unwrap_or_default();
+
+        if self.bots.is_bot(user_agent) {
+            Class::Crawler
+        } else {
+            Class::Human
+        }
+    }
+
+    async fn check(&self, peer: IpAddr, path: &str, headers: &HeaderMap) -> Result<(), Throttled> {
+        let class = self.class(path, headers);
+
+        let quota = match class {
+            Class::Crawler => self.crawlers,
+            Class::Human => self.humans,
+            Class::Api => self.api,
+        };
+
+        if quota.per_minute == 0 {
+            return Ok(());
+        }
+
+        let client = self.trusted_proxies.client(peer, headers);
+
+        let bucket = self
+            .buckets
+            .get_with((class, client), async {
+                Arc::new(Mutex::new(Bucket {
+                    tokens: quota.burst as f64,
+                    updated: Instant::now(),
+                }))
+            })
+            .await;
+
+        let result = bucket.lock().unwrap().take(quota);
+
+        result.map_err(|wait| {
+            tracing::debug!(%client, ?class, "rate limited client");
+            Throttled(wait)
+        })
+    }
+}
+
+/// Answers 429 with `Retry-After` once a client exceeds its budget, health checks and metrics
+/// are never limited.
+pub async fn rate_limit_middleware<B>(
+    State(state): State<Arc<PhixivState>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let path = request.uri().path();
+
+    if path.starts_with("/health") || path == "/metrics" {
+        return next.run(request).await;
+    }
+
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip())
+        .unwrap_or(IpAddr::from([0, 0, 0, 0]));
+
+    if let Err(throttled) = state
+        .rate_limiter
+        .check(peer, path, request.headers())
+        .await
+    {
+        return if path.starts_with("/api/") {
+            ApiError::from(throttled).into_response()
+        } else {
+            PhixivError::from(throttled).into_response()
+        };
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    fn forwarded_for(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for value in values {
+            headers.append("x-forwarded-for", value.parse().unwrap());
+        }
+
+        headers
+    }
+
+    #[test]
+    fn matches_ranges() {
+        let range: IpRange = "10.0.0.0/8".parse().unwrap();
+
+        assert!(range.contains(ip("10.1.2.3")));
+        assert!(!range.contains(ip("11.0.0.0")));
+        assert!(range.contains(ip("::ffff:10.1.2.3")));
+        assert!(!range.contains(ip("::1")));
+
+        let single: IpRange = "192.168.1.1".parse().unwrap();
+
+        assert!(single.contains(ip("192.168.1.1")));
+        assert!(!single.contains(ip("192.168.1.2")));
+
+        let everything: IpRange = "0.0.0.0/0".parse().unwrap();
+
+        assert!(everything.contains(ip("203.0.113.7")));
+
+        let v6: IpRange = "2001:db8::/32".parse().unwrap();
+
+        assert!(v6.contains(ip("2001:db8::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("::/129".parse::<IpRange>().is_err());
+        assert!("10.0.0/8".parse::<IpRange>().is_err());
+        assert!("10.0.0.0/x".parse::<TrustedProxies>().is_err());
+        assert!("".parse::<TrustedProxies>().unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_untrusted_peers() {
+        let proxies: TrustedProxies = "10.0.0.0/8".parse().unwrap();
+        let headers = forwarded_for(&["198.51.100.1"]);
+
+        assert_eq!(
+            proxies.client(ip("203.0.113.7"), &headers),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            TrustedProxies::default().client(ip("10.0.0.1"), &headers),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn resolves_clients_behind_trusted_proxies() {
+        let proxies: TrustedProxies = "10.0.0.0/8, 172.16.0.1".parse().unwrap();
+        let peer = ip("10.0.0.1");
+
+        // A client can prepend anything, only the address our proxies saw is believed
+        assert_eq!(
+            proxies.client(peer, &forwarded_for(&["1.1.1.1, 198.51.100.1, 172.16.0.1"])),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            proxies.client(peer, &forwarded_for(&["1.1.1.1", "198.51.100.1"])),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            proxies.client(peer, &forwarded_for(&["garbage, 198.51.100.1"])),
+            ip("198.51.100.1")
+        );
+
+        // Only trusted proxies in the chain, so the original client is the first of them
+        assert_eq!(
+            proxies.client(peer, &forwarded_for(&["10.0.0.2, 172.16.0.1"])),
+            ip("10.0.0.2")
+        );
+        assert_eq!(proxies.client(peer, &HeaderMap::new()), peer);
+    }
+}
@@ -0,0 +1,152 @@
+use std::{fmt, str::FromStr, sync::Arc};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    response::Response,
+};
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType};
+
+use crate::{
+    helper::{self, PhixivError},
+    pixiv::{self, ImageSize, Restriction},
+    state::PhixivState,
+    upstream::Upstream,
+};
+
+/// Width of blurred previews
+const BLUR_WIDTH: u32 = 600;
+
+/// Width the page is shrunk to before scaling back up, destroying any detail
+const BLUR_SAMPLE_WIDTH: u32 = 24;
+
+const BLUR_SIGMA: f32 = 12.0;
+
+const JPEG_QUALITY: u8 = 80;
+
+/// How a host embeds R-18 and R-18G works
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SensitivePolicy {
+    /// Embed exactly like any other work
+    Normal,
+    /// Embed with a heavily blurred preview in place of the image
+    Blur,
+    /// Embed the title and description without any image
+    Text,
+    /// Do not embed at all
+    Refuse,
+}
+
+impl FromStr for SensitivePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "normal" => Ok(Self::Normal),
+            "blur" => Ok(Self::Blur),
+            "text" => Ok(Self::Text),
+            "refuse" => Ok(Self::Refuse),
+            policy => anyhow::bail!("unknown sensitive content policy: {policy}"),
+        }
+    }
+}
+
+impl SensitivePolicy {
+    /// Whether a work with `restriction` is shown with its images under this policy.
+    pub fn shows(self, restriction: Restriction) -> bool {
+        restriction == Restriction::Safe || self == Self::Normal
+    }
+
+    /// Refuses a work with `restriction` when this policy does not embed it at all.
+    pub fn check(self, restriction: Restriction) -> Result<(), SensitiveRefused> {
+        if self == Self::Refuse && restriction != Restriction::Safe {
+            return Err(SensitiveRefused(restriction));
+        }
+
+        Ok(())
+    }
+}
+
+/// Returned when a host's policy refuses to embed a restricted work.
+#[derive(Debug)]
+pub struct SensitiveRefused(pub Restriction);
+
+impl fmt::Display for SensitiveRefused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "This work is marked {} and is not embedded here",
+            self.0.label().unwrap_or("sensitive")
+        )
+    }
+}
+
+impl std::error::Error for SensitiveRefused {}
+
+/// Size of the blurred preview rendered from a first page of size `page`.
+pub fn blur_size(page: ImageSize) -> ImageSize {
+    ImageSize {
+        width: BLUR_WIDTH,
+        height: (page.height as u64 * BLUR_WIDTH as u64 / page.width.max(1) as u64) as u32,
+    }
+}
+
+/// Renders a blurred preview of an artwork's first page, `file` is the illust id followed by `.jpg`.
+pub async fn blur_handler(
+    State(state): State<Arc<PhixivState>>,
+    Path(file): Path<String>,
+) -> Result<Response, PhixivError> {
+    state
+        .render_response(
+            "blur",
+            &file,
+            "jpg",
+            "image/jpeg",
+            |illust_id, access_token| render_blur(illust_id, access_token, state.upstream.clone()),
+        )
+        .await
+}
+
+async fn render_blur(
+    illust_id: String,
+    access_token: String,
+    upstream: Upstream,
+) -> anyhow::Result<Bytes> {
+    let page_urls = pixiv::page_urls(&illust_id, &access_token, &upstream).await?;
+
+    let Some(first_page) = page_urls.first() else {
+        anyhow::bail!("artwork has no pages");
+    };
+
+    let response = upstream
+        .send(
+            "pximg",
+            upstream
+                .client()
+                .get(first_page)
+                .headers(helper::pximg_headers(&access_token)?),
+        )
+        .await?;
+
+    let page = pixiv::check_status(response).await?.bytes().await?;
+
+    let preview = tokio::task::spawn_blocking(move || blur(&page)).await??;
+
+    Ok(preview.into())
+}
+
+/// Shrinks the page to a handful of pixels and scales it back up, leaving only rough colors.
+fn blur(page: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let image = image::load_from_memory(page)?;
+
+    let preview = image
+        .thumbnail(BLUR_SAMPLE_WIDTH, u32::MAX)
+        .resize(BLUR_WIDTH, u32::MAX, FilterType::Triangle)
+        .fast_blur(BLUR_SIGMA)
+        .into_rgb8();
+
+    let mut output = Vec::new();
+    JpegEncoder::new_with_quality(&mut output, JPEG_QUALITY).encode_image(&preview)?;
+
+    Ok(output)
+}
@@ -0,0 +1,328 @@
+use std::{
+    sync::{Arc, LazyLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Query, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hmac::{Hmac, Mac};
+use http::{Request, StatusCode};
+use regex::RegexSet;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::state::PhixivState;
+
+/// Bytes of the HMAC kept in urls, 128 bits
+const SIGNATURE_LENGTH: usize = 16;
+
+/// Path shapes the `/i` proxy will serve, relative to `/i/`
+static ALLOWED_PATHS: LazyLock<RegexSet> = LazyLock::new(|| {
+    const DATE: &str = r"\d{4}/\d{2}/\d{2}/\d{2}/\d{2}/\d{2}";
+
+    RegexSet::new([
+        format!(
+            r"^(c/[0-9a-z_]+/)?img-master/img/{DATE}/\d+_p\d+_(master|square)1200\.(jpg|png|gif)$"
+        ),
+        format!(r"^img-original/img/{DATE}/\d+_p\d+\.(jpg|png|gif)$"),
+        format!(r"^(c/[0-9a-z_]+/)?user-profile/img/{DATE}/[0-9A-Za-z_-]+\.(jpg|png|gif)$"),
+        format!(r"^img-zip-ugoira/img/{DATE}/\d+_ugoira\d+x\d+\.zip$"),
+        format!(r"^(c/[0-9a-z_]+/)?novel-cover-master/img/{DATE}/[0-9A-Za-z_]+\.(jpg|png|gif)$"),
+        String::from(r"^ugoira/\d+\.gif$"),
+        String::from(r"^mosaic/\d+\.jpg$"),
+        String::from(r"^blur/\d+\.jpg$"),
+    ])
+    .expect("allowed path patterns are valid")
+});
+
+/// Signs `/i` proxy paths so the proxy only serves urls phixiv handed out itself.
+#[derive(Clone)]
+pub struct UrlSigner {
+    key: Vec<u8>,
+    time_to_live: Option<Duration>,
+}
+
+/// Signature of a proxy url, along with the transform parameters it covers
+#[derive(Deserialize)]
+pub struct SignatureParams {
+    #[serde(rename = "s")]
+    pub signature: Option<String>,
+    #[serde(rename = "e")]
+    pub expires: Option<u64>,
+    pub w: Option<String>,
+    pub q: Option<String>,
+    pub fmt: Option<String>,
+}
+
+impl SignatureParams {
+    /// The transform parameters as signed, e.g. `w=480&fmt=jpg`, empty when there are none.
+    fn transform_query(&self) -> String {
+        [("w", &self.w), ("q", &self.q), ("fmt", &self.fmt)]
+            .into_iter()
+            .filter_map(|(name, value)| Some(format!("{name}={}", value.as_deref()?)))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+}
+
+impl UrlSigner {
+    pub fn new(key: Vec<u8>, time_to_live: Option<Duration>) -> Self {
+        Self { key, time_to_live }
+    }
+
+    /// MAC of a path, its expiry and any transform parameters, which are left out entirely when
+    /// empty so urls signed before transforms existed stay valid.
+    fn mac(&self, path: &str, expires: Option<u64>, transform_query: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("hmac accepts keys of any length");
+
+        mac.update(path.as_bytes());
+        mac.update(b"\n");
+        if let Some(expires) = expires {
+            mac.update(expires.to_string().as_bytes());
+        }
+
+        if !transform_query.is_empty() {
+            mac.update(b"\n");
+            mac.update(transform_query.as_bytes());
+        }
+
+        mac
+    }
+
+    /// Signs a proxy path such as `/i/img-master/...`, returning the absolute url for `host`.
+    pub fn signed_url(&self, host: &str, path: &str) -> String {
+        self.signed_transform_url(host, path, "")
+    }
+
+    /// Signs a proxy path along with transform parameters in the order `w`, `q`, `fmt`, such as
+    /// `w=480&fmt=jpg`, so clients cannot ask for variants phixiv did not hand out.
+    pub fn signed_transform_url(&self, host: &str, path: &str, transform_query: &str) -> String {
+        let proxied = path.trim_start_matches("/i/");
+
+        let expires = self.time_to_live.map(|time_to_live| {
+            (SystemTime::now() + time_to_live)
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+
+        let signature = self
+            .mac(proxied, expires, transform_query)
+            .finalize()
+            .into_bytes();
+
+        let signature = signature[..SIGNATURE_LENGTH]
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+
+        let query = match expires {
+            Some(expires) => format!("s={signature}&e={expires}"),
+            None => format!("s={signature}"),
+        };
+
+        if transform_query.is_empty() {
+            format!("https://{host}{path}?{query}")
+        } else {
+            format!("https://{host}{path}?{transform_query}&{query}")
+        }
+    }
+
+    /// Checks `path`, relative to `/i/`, is an allowed shape carrying a valid, unexpired signature
+    /// that covers its transform parameters.
+    pub fn verify(&self, path: &str, params: &SignatureParams) -> bool {
+        if !ALLOWED_PATHS.is_match(path) {
+            return false;
+        }
+
+        let Some(signature) = params.signature.as_deref().and_then(decode_hex) else {
+            return false;
+        };
+
+        if let Some(expires) = params.expires {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+
+            if now > expires {
+                return false;
+            }
+        }
+
+        signature.len() == SIGNATURE_LENGTH
+            && self
+                .mac(path, params.expires, &params.transform_query())
+                .verify_truncated_left(&signature)
+                .is_ok()
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Rejects `/i` requests for paths phixiv did not sign.
+pub async fn signature_middleware<B>(
+    State(state): State<Arc<PhixivState>>,
+    Query(params): Query<SignatureParams>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let path = request.uri().path().trim_start_matches('/');
+
+    if !state.signer.verify(path, &params) {
+        tracing::debug!(path, "rejected unsigned proxy request");
+        return (StatusCode::FORBIDDEN, "invalid signature").into_response();
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+
+    const PATH: &str = "img-master/img/2024/01/02/03/04/05/123_p0_master1200.jpg";
+
+    fn signer() -> UrlSigner {
+        UrlSigner::new(b"secret".to_vec(), None)
+    }
+
+    /// Splits a signed url into the path relative to `/i/` and its query.
+    fn parse(url: &str) -> (String, SignatureParams) {
+        let url = Url::parse(url).unwrap();
+        let query = |name| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.to_string())
+        };
+
+        let params = SignatureParams {
+            signature: query("s"),
+            expires: query("e").map(|expires| expires.parse().unwrap()),
+            w: query("w"),
+            q: query("q"),
+            fmt: query("fmt"),
+        };
+
+        (url.path().trim_start_matches("/i/").to_string(), params)
+    }
+
+    #[test]
+    fn verifies_signed_urls() {
+        let signer = signer();
+        let (path, params) = parse(&signer.signed_url("phixiv.net", &format!("/i/{PATH}")));
+
+        assert_eq!(path, PATH);
+        assert!(signer.verify(&path, &params));
+        assert!(!UrlSigner::new(b"other".to_vec(), None).verify(&path, &params));
+    }
+
+    #[test]
+    fn rejects_disallowed_paths() {
+        let signer = signer();
+
+        for path in [
+            "img-master/img/2024/01/02/03/04/05/123_p0_master1200.exe",
+            "../etc/passwd",
+            "mosaic/abc.jpg",
+        ] {
+            let (_, params) = parse(&signer.signed_url("phixiv.net", &format!("/i/{path}")));
+
+            assert!(!signer.verify(path, &params), "{path}");
+        }
+
+        for path in ["ugoira/1.gif", "mosaic/1.jpg", "blur/1.jpg"] {
+            let (_, params) = parse(&signer.signed_url("phixiv.net", &format!("/i/{path}")));
+
+            assert!(signer.verify(path, &params), "{path}");
+        }
+    }
+
+    #[test]
+    fn rejects_expired_signatures() {
+        let signer = UrlSigner::new(b"secret".to_vec(), Some(Duration::from_secs(60)));
+        let (path, mut params) = parse(&signer.signed_url("phixiv.net", &format!("/i/{PATH}")));
+
+        assert!(params.expires.is_some());
+        assert!(signer.verify(&path, &params));
+
+        let signature = signer.mac(&path, Some(1), "").finalize().into_bytes();
+        params.signature = Some(
+            signature[..SIGNATURE_LENGTH]
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect(),
+        );
+        params.expires = Some(1);
+
+        assert!(!signer.verify(&path, &params));
+    }
+
+    #[test]
+    fn rejects_malformed_signatures() {
+        let signer = signer();
+        let (path, mut params) = parse(&signer.signed_url("phixiv.net", &format!("/i/{PATH}")));
+        let signature = params.signature.take().unwrap();
+
+        for malformed in [
+            &signature[..signature.len() - 2],
+            &signature[..signature.len() - 1],
+            "",
+            "zz",
+        ] {
+            params.signature = Some(malformed.to_string());
+
+            assert!(!signer.verify(&path, &params), "{malformed}");
+        }
+
+        params.signature = None;
+
+        assert!(!signer.verify(&path, &params));
+    }
+
+    #[test]
+    fn decodes_hex() {
+        assert_eq!(decode_hex("00ff7a"), Some(vec![0x00, 0xff, 0x7a]));
+        assert_eq!(decode_hex(""), Some(Vec::new()));
+        assert_eq!(decode_hex("abc"), None);
+        assert_eq!(decode_hex("0g"), None);
+        assert_eq!(decode_hex("aéa"), None);
+    }
+
+    #[test]
+    fn signs_transform_parameters() {
+        let signer = signer();
+        let url = signer.signed_transform_url("phixiv.net", &format!("/i/{PATH}"), "w=480&fmt=jpg");
+        let (path, mut params) = parse(&url);
+
+        assert_eq!(params.w.as_deref(), Some("480"));
+        assert!(signer.verify(&path, &params));
+
+        params.q = Some(String::from("95"));
+        assert!(!signer.verify(&path, &params));
+
+        params.q = None;
+        params.fmt = None;
+        assert!(!signer.verify(&path, &params));
+
+        let (path, mut params) = parse(&signer.signed_url("phixiv.net", &format!("/i/{PATH}")));
+        params.w = Some(String::from("2400"));
+
+        assert!(!signer.verify(&path, &params));
+    }
+}
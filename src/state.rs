@@ -1,41 +1,176 @@
-use std::sync::Arc;
+use std::{future::Future, sync::Arc, time::Duration};
 
-use axum::{extract::State, middleware::Next, response::Response};
-use http::Request;
+use axum::{
+    body::Bytes,
+    headers::CacheControl,
+    response::{IntoResponse, Response},
+    TypedHeader,
+};
+use http::header;
+use moka::future::Cache;
 use reqwest::Client;
-use tokio::sync::RwLock;
 
-use crate::{auth::PixivAuth, helper::PhixivError};
+use crate::{
+    accounts::AccountPool,
+    config::Config,
+    disk_cache::DiskCache,
+    download::Downloads,
+    health::UpstreamProbe,
+    helper::{InvalidRequest, PhixivError, SharedError},
+    metrics,
+    pixiv::ListingCache,
+    rate_limit::RateLimiter,
+    signature::UrlSigner,
+    token_store::TokenStore,
+    upstream::Upstream,
+};
 
-#[derive(Clone)]
 pub struct PhixivState {
-    pub auth: PixivAuth,
-    pub client: Client,
+    pub config: Config,
+    pub accounts: AccountPool,
+    pub upstream: Upstream,
+    /// Images rendered by phixiv itself (ugoira, mosaics, blurs, transforms), keyed by their
+    /// `/i` path
+    pub renders: Cache<String, Bytes>,
+    pub listings: ListingCache,
+    /// Proxied images kept on disk, disabled unless `IMAGE_CACHE_DIR` is set
+    pub images: Option<DiskCache>,
+    pub downloads: Downloads,
+    pub signer: UrlSigner,
+    pub probe: UpstreamProbe,
+    pub rate_limiter: RateLimiter,
 }
 
 impl PhixivState {
-    pub async fn login(refresh_token: String) -> anyhow::Result<Self> {
+    pub async fn login(config: Config) -> anyhow::Result<Self> {
         let client = Client::new();
 
-        let auth = PixivAuth::login(&client, refresh_token).await?;
+        let token_store = match &config.token_store_path {
+            Some(path) => Some(Arc::new(
+                TokenStore::open(path, config.token_store_key.as_deref()).await?,
+            )),
+            None => None,
+        };
 
-        Ok(Self { auth, client })
-    }
+        let accounts = AccountPool::login(
+            &client,
+            config.refresh_tokens.clone(),
+            token_store,
+            config.account_selection,
+            config.account_bench_time,
+        )
+        .await?;
+
+        let renders = Cache::builder()
+            .weigher(|_, image: &Bytes| image.len().try_into().unwrap_or(u32::MAX))
+            .max_capacity(config.render_cache_size * 1024 * 1024)
+            .time_to_live(Duration::from_secs(60 * 60 * 24))
+            .build();
+
+        let listings = ListingCache::new(
+            config.listing_cache_size,
+            config.listing_cache_ttl,
+            config.listing_not_found_ttl,
+        );
+
+        let images = match &config.image_cache_dir {
+            Some(directory) => {
+                Some(DiskCache::open(directory, config.image_cache_size * 1024 * 1024).await?)
+            }
+            None => None,
+        };
+
+        let signing_key = match &config.proxy_secret {
+            Some(secret) => secret.clone().into_bytes(),
+            None => {
+                tracing::warn!(
+                    "PROXY_SECRET is not set, proxy urls will stop working when phixiv restarts"
+                );
+                rand::random::<[u8; 32]>().to_vec()
+            }
+        };
+
+        let signer = UrlSigner::new(signing_key, config.proxy_url_ttl);
+
+        let rate_limiter = RateLimiter::new(&config);
+
+        let upstream = Upstream::new(
+            client,
+            config.upstream_concurrency,
+            config.upstream_queue_timeout,
+        );
 
-    async fn refresh(&mut self) -> anyhow::Result<()> {
-        self.auth.refresh(&self.client).await
+        Ok(Self {
+            config,
+            accounts,
+            upstream,
+            renders,
+            listings,
+            images,
+            downloads: Downloads::default(),
+            signer,
+            probe: UpstreamProbe::default(),
+            rate_limiter,
+        })
     }
-}
 
-pub async fn authorized_middleware<B>(
-    State(state): State<Arc<RwLock<PhixivState>>>,
-    request: Request<B>,
-    next: Next<B>,
-) -> Result<Response, PhixivError> {
-    if state.read().await.auth.expired() {
-        let mut state = state.write().await;
-        state.refresh().await?;
+    /// Gets the image rendered under `key`, running `render` on a miss. Concurrent misses for the
+    /// same key wait on a single render.
+    pub async fn render(
+        &self,
+        key: String,
+        render: impl Future<Output = anyhow::Result<Bytes>>,
+    ) -> anyhow::Result<Bytes> {
+        let entry = self
+            .renders
+            .entry(key)
+            .or_try_insert_with(render)
+            .await
+            .map_err(SharedError)?;
+
+        metrics::METRICS.cache_lookup("renders", !entry.is_fresh());
+
+        Ok(entry.into_value())
     }
 
-    Ok(next.run(request).await)
+    /// Serves the `kind` image phixiv renders for an artwork, `file` is the illust id followed by
+    /// `.{extension}`. `render` is given the illust id and an access token on a cache miss.
+    pub async fn render_response<F>(
+        &self,
+        kind: &str,
+        file: &str,
+        extension: &str,
+        content_type: &'static str,
+        render: impl Fn(String, String) -> F,
+    ) -> Result<Response, PhixivError>
+    where
+        F: Future<Output = anyhow::Result<Bytes>>,
+    {
+        let Some(illust_id) = file
+            .strip_suffix(extension)
+            .and_then(|name| name.strip_suffix('.'))
+        else {
+            return Err(InvalidRequest(format!("unsupported {kind} format: {file}")).into());
+        };
+
+        let account = self.accounts.lease()?;
+
+        let image = self
+            .render(
+                format!("{kind}/{file}"),
+                account.track(|access_token| render(illust_id.to_string(), access_token)),
+            )
+            .await?;
+
+        Ok((
+            TypedHeader(
+                CacheControl::new()
+                    .with_max_age(Duration::from_secs(60 * 60 * 24))
+                    .with_public(),
+            ),
+            [(header::CONTENT_TYPE, content_type)],
+            image,
+        )
+            .into_response())
+    }
 }
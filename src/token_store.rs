@@ -0,0 +1,109 @@
+use std::{collections::HashMap, io, path::PathBuf};
+
+use anyhow::Context;
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+const NONCE_LENGTH: usize = 12;
+
+/// File keeping the refresh tokens pixiv rotates on every refresh, so a restart does not fall
+/// back to a configured token pixiv may have already replaced.
+///
+/// Tokens are keyed by a hash of the configured token they descend from, so changing the
+/// configured token starts afresh. The file is only readable by its owner, and is encrypted
+/// with ChaCha20-Poly1305 when a key is given.
+pub struct TokenStore {
+    path: PathBuf,
+    cipher: Option<ChaCha20Poly1305>,
+    tokens: Mutex<HashMap<String, String>>,
+}
+
+impl TokenStore {
+    /// Loads the store at `path`, starting empty if the file does not exist yet.
+    pub async fn open(path: impl Into<PathBuf>, key: Option<&str>) -> anyhow::Result<Self> {
+        let path = path.into();
+
+        let cipher = key.map(|key| ChaCha20Poly1305::new(&Sha256::digest(key.as_bytes())));
+
+        let tokens = match fs::read(&path).await {
+            Ok(contents) => {
+                let contents = match &cipher {
+                    Some(cipher) => decrypt(cipher, &contents)?,
+                    None => contents,
+                };
+
+                serde_json::from_slice(&contents)
+                    .with_context(|| format!("invalid token store {}", path.display()))?
+            }
+            Err(error) if error.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(error) => return Err(error.into()),
+        };
+
+        Ok(Self {
+            path,
+            cipher,
+            tokens: Mutex::new(tokens),
+        })
+    }
+
+    /// Key of the tokens descending from a configured refresh token.
+    pub fn key(configured_token: &str) -> String {
+        format!("{:x}", Sha256::digest(configured_token.as_bytes()))
+    }
+
+    pub async fn get(&self, key: &str) -> Option<String> {
+        self.tokens.lock().await.get(key).cloned()
+    }
+
+    /// Records the latest refresh token for `key` and rewrites the file.
+    pub async fn save(&self, key: &str, refresh_token: &str) -> anyhow::Result<()> {
+        let mut tokens = self.tokens.lock().await;
+
+        tokens.insert(key.to_string(), refresh_token.to_string());
+
+        let mut contents = serde_json::to_vec(&*tokens)?;
+
+        if let Some(cipher) = &self.cipher {
+            contents = encrypt(cipher, &contents)?;
+        }
+
+        // Written beside the store and renamed over it, so a crash never leaves it truncated
+        let temp_path = self.path.with_extension("tmp");
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        let mut file = options.open(&temp_path).await?;
+        file.write_all(&contents).await?;
+        file.sync_all().await?;
+
+        fs::rename(&temp_path, &self.path).await?;
+
+        Ok(())
+    }
+}
+
+fn encrypt(cipher: &ChaCha20Poly1305, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let nonce = rand::random::<[u8; NONCE_LENGTH]>();
+
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| anyhow::anyhow!("failed to encrypt token store"))?;
+
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn decrypt(cipher: &ChaCha20Poly1305, contents: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if contents.len() < NONCE_LENGTH {
+        anyhow::bail!("token store is truncated");
+    }
+
+    let (nonce, ciphertext) = contents.split_at(NONCE_LENGTH);
+
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow::anyhow!("failed to decrypt token store, is TOKEN_STORE_KEY correct?"))
+}
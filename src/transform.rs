@@ -0,0 +1,267 @@
+use image::{
+    codecs::{avif::AvifEncoder, jpeg::JpegEncoder, png::PngEncoder, webp::WebPEncoder},
+    imageops::FilterType,
+    DynamicImage,
+};
+use serde::Deserialize;
+
+use url::{Position, Url};
+
+use crate::{helper::InvalidRequest, pixiv::ImageSize, signature::UrlSigner};
+
+/// Widths the proxy will produce, requests are rounded down to one of them so signed urls can
+/// only ask for a handful of variants of each image. The widest is twice pixiv's 1200px masters.
+const WIDTHS: [u32; 12] = [
+    100, 150, 250, 320, 480, 640, 800, 1024, 1280, 1600, 2048, 2400,
+];
+
+/// Qualities the proxy will encode at, requests are rounded to the nearest
+const QUALITIES: [u8; 4] = [40, 60, 80, 95];
+
+const DEFAULT_QUALITY: u8 = 80;
+
+/// Fastest AVIF encoder speed, AVIF is by far the most expensive format to encode
+const AVIF_SPEED: u8 = 10;
+
+/// Query parameters asking the `/i` proxy to resize or re-encode an image
+#[derive(Deserialize)]
+pub struct TransformParams {
+    pub w: Option<u32>,
+    pub q: Option<u8>,
+    pub fmt: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+    WebP,
+    Avif,
+}
+
+/// A resolved resize and re-encode of a proxied image.
+#[derive(Clone, Copy)]
+pub struct Transform {
+    pub width: Option<u32>,
+    pub quality: u8,
+    pub format: OutputFormat,
+    /// Whether `format` was picked from the request's `Accept` header
+    pub negotiated: bool,
+}
+
+impl OutputFormat {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "jpg" | "jpeg" => Some(Self::Jpeg),
+            "png" => Some(Self::Png),
+            "webp" => Some(Self::WebP),
+            "avif" => Some(Self::Avif),
+            _ => None,
+        }
+    }
+
+    /// Format matching the extension of a pximg path, GIFs are re-encoded as PNG.
+    fn from_path(path: &str) -> Self {
+        if path.ends_with(".jpg") || path.ends_with(".jpeg") {
+            Self::Jpeg
+        } else {
+            Self::Png
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Png => "png",
+            Self::WebP => "webp",
+            Self::Avif => "avif",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::Png => "image/png",
+            Self::WebP => "image/webp",
+            Self::Avif => "image/avif",
+        }
+    }
+}
+
+/// Whether an `Accept` header lists `content_type` with a nonzero quality.
+fn accepts(accept: &str, content_type: &str) -> bool {
+    accept.split(',').any(|range| {
+        let mut parts = range.split(';').map(str::trim);
+
+        parts.next() == Some(content_type)
+            && parts.all(|parameter| {
+                parameter
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_none_or(|q| q > 0.0)
+            })
+    })
+}
+
+/// The widest of [`WIDTHS`] no wider than `width`, or the narrowest.
+pub fn width_bucket(width: u32) -> u32 {
+    WIDTHS
+        .into_iter()
+        .rev()
+        .find(|&bucket| bucket <= width)
+        .unwrap_or(WIDTHS[0])
+}
+
+/// Fits `original` within `bounds`, narrowing scaled down images to a width the proxy produces
+/// so the size reported is the size served.
+pub fn scaled_size(original: ImageSize, bounds: ImageSize) -> ImageSize {
+    let size = original.fit(bounds);
+
+    if size == original {
+        return size;
+    }
+
+    original.fit(ImageSize {
+        width: width_bucket(size.width),
+        height: u32::MAX,
+    })
+}
+
+/// Asks the proxy at the signed `url` for `size` when it is smaller than the `original` image,
+/// in the format named `format`, or one negotiated with the client when `None`. The parameters
+/// are signed along with the path.
+pub fn scaled_url(
+    signer: &UrlSigner,
+    url: &str,
+    size: ImageSize,
+    original: ImageSize,
+    format: Option<&str>,
+) -> String {
+    if size == original {
+        return url.to_string();
+    }
+
+    let Ok(parsed) = Url::parse(url) else {
+        return url.to_string();
+    };
+
+    let transform_query = match format {
+        Some(format) => format!("w={}&fmt={format}", size.width),
+        None => format!("w={}", size.width),
+    };
+
+    signer.signed_transform_url(
+        &parsed[Position::BeforeHost..Position::AfterPort],
+        parsed.path(),
+        &transform_query,
+    )
+}
+
+fn quality_bucket(quality: u8) -> u8 {
+    QUALITIES
+        .into_iter()
+        .min_by_key(|&bucket| bucket.abs_diff(quality))
+        .unwrap_or(DEFAULT_QUALITY)
+}
+
+impl Transform {
+    /// Resolves the transform requested for the image at `path`, `None` when no parameters are given.
+    ///
+    /// Without an explicit `fmt`, or with `fmt=auto`, the format is negotiated from `accept`: AVIF
+    /// when supported, otherwise WebP for lossless sources, otherwise the source's own format.
+    /// The WebP encoder is lossless only, so for JPEG sources, where it would be larger than the
+    /// source, `fmt=webp` is taken as a hint and negotiated as AVIF or the source's own format.
+    pub fn from_params(
+        params: &TransformParams,
+        accept: Option<&str>,
+        path: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        if params.w.is_none() && params.q.is_none() && params.fmt.is_none() {
+            return Ok(None);
+        }
+
+        let source = OutputFormat::from_path(path);
+
+        let (format, negotiated) = match params.fmt.as_deref() {
+            None | Some("auto") => {
+                let accept = accept.unwrap_or_default();
+
+                let format = if accepts(accept, "image/avif") {
+                    OutputFormat::Avif
+                } else if source == OutputFormat::Png && accepts(accept, "image/webp") {
+                    OutputFormat::WebP
+                } else {
+                    source
+                };
+
+                (format, true)
+            }
+            Some(name) => match OutputFormat::from_name(name) {
+                Some(OutputFormat::WebP) if source == OutputFormat::Jpeg => {
+                    let format = if accepts(accept.unwrap_or_default(), "image/avif") {
+                        OutputFormat::Avif
+                    } else {
+                        source
+                    };
+
+                    (format, true)
+                }
+                Some(format) => (format, false),
+                None => {
+                    return Err(InvalidRequest(format!("unsupported image format: {name}")).into())
+                }
+            },
+        };
+
+        Ok(Some(Self {
+            width: params.w.map(width_bucket),
+            quality: quality_bucket(params.q.unwrap_or(DEFAULT_QUALITY)),
+            format,
+            negotiated,
+        }))
+    }
+
+    /// Key identifying this variant of the image at `path`.
+    pub fn cache_key(&self, path: &str) -> String {
+        format!(
+            "transform/{path}?w={}&q={}&fmt={}",
+            self.width.unwrap_or_default(),
+            self.quality,
+            self.format.name()
+        )
+    }
+
+    /// Decodes `source`, scales it down to the requested width and re-encodes it, never upscaling.
+    pub fn apply(&self, source: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut image = image::load_from_memory(source)?;
+
+        if let Some(width) = self.width.filter(|&width| width < image.width()) {
+            image = image.resize(width, u32::MAX, FilterType::Lanczos3);
+        }
+
+        // Encoders only take 8 bit RGB(A), and JPEG has no alpha channel
+        let image = if image.color().has_alpha() && self.format != OutputFormat::Jpeg {
+            DynamicImage::ImageRgba8(image.into_rgba8())
+        } else {
+            DynamicImage::ImageRgb8(image.into_rgb8())
+        };
+
+        let mut output = Vec::new();
+
+        match self.format {
+            OutputFormat::Jpeg => image
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut output, self.quality))?,
+            OutputFormat::Png => image.write_with_encoder(PngEncoder::new(&mut output))?,
+            OutputFormat::WebP => {
+                image.write_with_encoder(WebPEncoder::new_lossless(&mut output))?
+            }
+            OutputFormat::Avif => image.write_with_encoder(AvifEncoder::new_with_speed_quality(
+                &mut output,
+                AVIF_SPEED,
+                self.quality,
+            ))?,
+        }
+
+        Ok(output)
+    }
+}
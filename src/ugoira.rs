@@ -0,0 +1,93 @@
+use std::{
+    io::{Cursor, Read},
+    sync::Arc,
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    response::Response,
+};
+use image::{
+    codecs::gif::{GifEncoder, Repeat},
+    Delay, Frame,
+};
+use zip::ZipArchive;
+
+use crate::{
+    helper::{self, PhixivError},
+    pixiv::{self, UgoiraFrame},
+    state::PhixivState,
+    upstream::Upstream,
+};
+
+/// Renders an ugoira as an animated GIF, `file` is the illust id followed by `.gif`.
+pub async fn ugoira_handler(
+    State(state): State<Arc<PhixivState>>,
+    Path(file): Path<String>,
+) -> Result<Response, PhixivError> {
+    state
+        .render_response(
+            "ugoira",
+            &file,
+            "gif",
+            "image/gif",
+            |illust_id, access_token| {
+                render_ugoira(illust_id, access_token, state.upstream.clone())
+            },
+        )
+        .await
+}
+
+async fn render_ugoira(
+    illust_id: String,
+    access_token: String,
+    upstream: Upstream,
+) -> anyhow::Result<Bytes> {
+    let metadata = pixiv::ugoira_metadata(&illust_id, &access_token, &upstream).await?;
+
+    let response = upstream
+        .send(
+            "pximg",
+            upstream
+                .client()
+                .get(&metadata.zip_url)
+                .headers(helper::pximg_headers(&access_token)?),
+        )
+        .await?;
+
+    let archive = pixiv::check_status(response).await?.bytes().await?;
+
+    let gif = tokio::task::spawn_blocking(move || encode_gif(archive, metadata.frames)).await??;
+
+    Ok(gif.into())
+}
+
+/// Decodes every frame out of the ugoira archive and re-encodes them as a looping GIF.
+fn encode_gif(archive: Bytes, frames: Vec<UgoiraFrame>) -> anyhow::Result<Vec<u8>> {
+    let mut archive = ZipArchive::new(Cursor::new(archive))?;
+    let mut gif = Vec::new();
+
+    {
+        let mut encoder = GifEncoder::new_with_speed(&mut gif, 10);
+        encoder.set_repeat(Repeat::Infinite)?;
+
+        let mut buffer = Vec::new();
+
+        for frame in frames {
+            buffer.clear();
+            archive.by_name(&frame.file)?.read_to_end(&mut buffer)?;
+
+            let image = image::load_from_memory(&buffer)?.into_rgba8();
+
+            encoder.encode_frame(Frame::from_parts(
+                image,
+                0,
+                0,
+                Delay::from_numer_denom_ms(frame.delay, 1),
+            ))?;
+        }
+    }
+
+    Ok(gif)
+}
@@ -0,0 +1,119 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use futures::StreamExt;
+use reqwest::{Client, RequestBuilder, ResponseBuilderExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::metrics;
+
+/// The HTTP client for pixiv, capping requests in flight.
+#[derive(Clone)]
+pub struct Upstream {
+    client: Client,
+    /// `None` when unlimited
+    permits: Option<Arc<Semaphore>>,
+    /// How long a request may wait for a permit before it is shed
+    queue_timeout: Duration,
+}
+
+/// Returned when too many requests to pixiv are already in flight to wait for a turn.
+#[derive(Debug)]
+pub struct Overloaded;
+
+impl fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "too many requests to pixiv in flight")
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+impl Upstream {
+    /// Caps requests in flight through `client` at `max`, unlimited when 0.
+    pub fn new(client: Client, max: usize, queue_timeout: Duration) -> Self {
+        Self {
+            client,
+            permits: (max > 0).then(|| Arc::new(Semaphore::new(max))),
+            queue_timeout,
+        }
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Sends a request to pixiv once a permit is free, shedding it with [`Overloaded`] if none
+    /// frees up in time. The permit is held until the response body is consumed or dropped, for
+    /// bodies phixiv reads itself.
+    pub async fn send(
+        &self,
+        endpoint: &'static str,
+        request: RequestBuilder,
+    ) -> anyhow::Result<reqwest::Response> {
+        let permit = self.acquire(endpoint).await?;
+
+        let response = metrics::upstream(endpoint, request).await?;
+
+        match permit {
+            Some(permit) => hold_permit(response, permit),
+            None => Ok(response),
+        }
+    }
+
+    /// Like [`Upstream::send`], but releases the permit once the response head arrives, for
+    /// bodies relayed to clients at their own pace, which would otherwise let slow clients hold
+    /// every permit.
+    pub async fn send_relayed(
+        &self,
+        endpoint: &'static str,
+        request: RequestBuilder,
+    ) -> anyhow::Result<reqwest::Response> {
+        let _permit = self.acquire(endpoint).await?;
+
+        Ok(metrics::upstream(endpoint, request).await?)
+    }
+
+    async fn acquire(
+        &self,
+        endpoint: &'static str,
+    ) -> anyhow::Result<Option<OwnedSemaphorePermit>> {
+        let Some(permits) = &self.permits else {
+            return Ok(None);
+        };
+
+        let permit = tokio::time::timeout(self.queue_timeout, permits.clone().acquire_owned())
+            .await
+            .map_err(|_| {
+                metrics::METRICS
+                    .upstream_errors
+                    .with_label_values(&[endpoint, "shed"])
+                    .inc();
+
+                Overloaded
+            })??;
+
+        Ok(Some(permit))
+    }
+}
+
+/// Rebuilds `response` with a body that releases `permit` once it is dropped.
+fn hold_permit(
+    response: reqwest::Response,
+    permit: OwnedSemaphorePermit,
+) -> anyhow::Result<reqwest::Response> {
+    let mut builder = http::Response::builder()
+        .status(response.status())
+        .version(response.version())
+        .url(response.url().clone());
+
+    if let Some(headers) = builder.headers_mut() {
+        *headers = response.headers().clone();
+    }
+
+    let body = response.bytes_stream().map(move |chunk| {
+        let _permit = &permit;
+        chunk
+    });
+
+    Ok(builder.body(reqwest::Body::wrap_stream(body))?.into())
+}